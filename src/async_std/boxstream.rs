@@ -8,7 +8,7 @@ use log::trace;
 use std::cmp;
 
 use crate::{
-    boxstream::{BoxStreamRecv, BoxStreamSend, Decrypted, KeyNonce, MSG_BODY_MAX_LEN},
+    boxstream::{self, BoxStreamRecv, BoxStreamSend, Decrypted, KeyNonce, MSG_BODY_MAX_LEN},
     handshake::HandshakeComplete,
 };
 
@@ -123,6 +123,11 @@ where
 
         futures::ready!(assert_not_closed(&this.status))?;
 
+        // a previous decryption error left the boxstream closed
+        if this.bs_recv.is_poisoned() {
+            return Poll::Ready(Err(boxstream::Error::StreamClosed.into()));
+        }
+
         // if there's no data pending to read from deciphered text, read from underlying reader
         while this.plain_off == this.plain_len {
             trace!(target:"ssb-handshake","  reset");
@@ -428,6 +433,38 @@ mod test {
         Ok(())
     }
 
+    #[async_std::test]
+    async fn test_asyncbox_poisoned() -> io::Result<()> {
+        let key_nonce = || {
+            KeyNonce::new(
+                secretbox::Key(sha256::hash(&[0]).0),
+                secretbox::Nonce([0u8; 24]),
+            )
+        };
+
+        let mut stream = CircularBuffer::new(16384);
+        let mut sender = BoxStreamSend::new(key_nonce());
+        let mut enc = [0; 64];
+        let (_, n) = sender.encrypt(b"nice", &mut enc).unwrap();
+        enc[n - 1] ^= 0xff;
+        stream.write_all(&enc[..n]).await?;
+        let (_, n) = sender.encrypt(b"ssbing", &mut enc).unwrap();
+        stream.write_all(&enc[..n]).await?;
+        let mut reader = BoxStreamRead::new(stream, key_nonce(), 16384);
+
+        // The corrupted message fails, and then the boxstream is closed
+        let mut buf = [0u8; 10];
+        let err = reader.read(&mut buf).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        let err = reader.read(&mut buf).await.unwrap_err();
+        assert_eq!(
+            Some(&boxstream::Error::StreamClosed),
+            err.get_ref().and_then(|e| e.downcast_ref())
+        );
+
+        Ok(())
+    }
+
     use async_std::io::{Read, Write};
     use test_utils::net_async::{net, net_fragment};
 
@@ -1,13 +1,13 @@
 use super::error::{Error, Result};
 use async_std::{
-    io,
+    future, io,
     io::{Read, Write},
     prelude::*,
 };
 use sodiumoxide::crypto::{auth, sign::ed25519};
-use std::convert;
+use std::{convert, time::Duration};
 
-use crate::handshake::{self, Handshake, HandshakeComplete};
+use crate::handshake::{self, Handshake, HandshakeComplete, RecvClientHello, SendClientHello};
 
 impl convert::From<io::Error> for Error {
     fn from(error: io::Error) -> Self {
@@ -28,12 +28,24 @@ pub async fn handshake_client<T: Read + Write + Unpin>(
     sk: ed25519::SecretKey,
     server_pk: ed25519::PublicKey,
 ) -> Result<HandshakeComplete> {
+    crate::init::ensure_initialized()?;
+    handshake_client_with(stream, Handshake::new_client(net_id, pk, sk), server_pk).await
+}
+
+/// Perform the client side of the handshake over `stream` starting from an already created
+/// `handshake`, which allows configuring it before running it.
+pub async fn handshake_client_with<T: Read + Write + Unpin>(
+    stream: &mut T,
+    handshake: Handshake<SendClientHello>,
+    server_pk: ed25519::PublicKey,
+) -> Result<HandshakeComplete> {
+    crate::init::ensure_initialized()?;
     let mut buf = [0; 128];
-    let handshake = Handshake::new_client(net_id, pk, sk);
 
     let mut send_buf = &mut buf[..handshake.send_bytes()];
     let handshake = handshake.send_client_hello(&mut send_buf);
     stream.write_all(&send_buf).await?;
+    stream.flush().await?;
 
     let mut recv_buf = &mut buf[..handshake.recv_bytes()];
     stream.read_exact(&mut recv_buf).await?;
@@ -42,6 +54,7 @@ pub async fn handshake_client<T: Read + Write + Unpin>(
     let mut send_buf = &mut buf[..handshake.send_bytes()];
     let handshake = handshake.send_client_auth(&mut send_buf, server_pk)?;
     stream.write_all(&send_buf).await?;
+    stream.flush().await?;
 
     let mut recv_buf = &mut buf[..handshake.recv_bytes()];
     stream.read_exact(&mut recv_buf).await?;
@@ -56,26 +69,84 @@ pub async fn handshake_server<T: Read + Write + Unpin>(
     pk: ed25519::PublicKey,
     sk: ed25519::SecretKey,
 ) -> Result<HandshakeComplete> {
+    crate::init::ensure_initialized()?;
+    handshake_server_with(stream, Handshake::new_server(net_id, pk, sk)).await
+}
+
+/// Perform the server side of the handshake over `stream` starting from an already created
+/// `handshake`, which allows configuring it before running it.
+pub async fn handshake_server_with<T: Read + Write + Unpin>(
+    stream: &mut T,
+    handshake: Handshake<RecvClientHello>,
+) -> Result<HandshakeComplete> {
+    handshake_server_aux(stream, handshake, None, None).await
+}
+
+/// Like [`handshake_server_with`], but giving up with an `io::ErrorKind::TimedOut` error if the
+/// client hello is not received within `hello_timeout`, or if the rest of the handshake is not
+/// completed within `timeout`.
+///
+/// The client hello is sent by a yet unauthenticated peer, so `hello_timeout` should be short to
+/// quickly drop peers that connect and then stall or send bytes slowly.
+pub async fn handshake_server_timeout<T: Read + Write + Unpin>(
+    stream: &mut T,
+    handshake: Handshake<RecvClientHello>,
+    hello_timeout: Duration,
+    timeout: Duration,
+) -> Result<HandshakeComplete> {
+    handshake_server_aux(stream, handshake, Some(hello_timeout), Some(timeout)).await
+}
+
+// Run `fut`, failing with an `io::ErrorKind::TimedOut` error if it doesn't complete within `dur`.
+async fn with_timeout<F, T>(dur: Option<Duration>, fut: F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    match dur {
+        Some(dur) => future::timeout(dur, fut).await.map_err(|_| {
+            Error::Io(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "handshake timed out",
+            ))
+        })?,
+        None => fut.await,
+    }
+}
+
+async fn handshake_server_aux<T: Read + Write + Unpin>(
+    stream: &mut T,
+    handshake: Handshake<RecvClientHello>,
+    hello_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+) -> Result<HandshakeComplete> {
+    crate::init::ensure_initialized()?;
     let mut buf = [0; 128];
-    let handshake = Handshake::new_server(net_id, pk, sk);
 
-    let mut recv_buf = &mut buf[..handshake.recv_bytes()];
-    stream.read_exact(&mut recv_buf).await?;
-    let handshake = handshake.recv_client_hello(&recv_buf)?;
+    let handshake = with_timeout(hello_timeout, async {
+        let mut recv_buf = &mut buf[..handshake.recv_bytes()];
+        stream.read_exact(&mut recv_buf).await?;
+        Ok(handshake.recv_client_hello(&recv_buf)?)
+    })
+    .await?;
 
-    let mut send_buf = &mut buf[..handshake.send_bytes()];
-    let handshake = handshake.send_server_hello(&mut send_buf);
-    stream.write_all(&send_buf).await?;
+    with_timeout(timeout, async {
+        let mut send_buf = &mut buf[..handshake.send_bytes()];
+        let handshake = handshake.send_server_hello(&mut send_buf);
+        stream.write_all(&send_buf).await?;
+        stream.flush().await?;
 
-    let mut recv_buf = &mut buf[..handshake.recv_bytes()];
-    stream.read_exact(&mut recv_buf).await?;
-    let handshake = handshake.recv_client_auth(&mut recv_buf)?;
+        let mut recv_buf = &mut buf[..handshake.recv_bytes()];
+        stream.read_exact(&mut recv_buf).await?;
+        let handshake = handshake.recv_client_auth(&mut recv_buf)?;
 
-    let mut send_buf = &mut buf[..handshake.send_bytes()];
-    let handshake = handshake.send_server_accept(&mut send_buf);
-    stream.write_all(&send_buf).await?;
+        let mut send_buf = &mut buf[..handshake.send_bytes()];
+        let handshake = handshake.send_server_accept(&mut send_buf);
+        stream.write_all(&send_buf).await?;
+        stream.flush().await?;
 
-    Ok(handshake.complete())
+        Ok(handshake.complete())
+    })
+    .await
 }
 
 #[cfg(test)]
@@ -129,6 +200,176 @@ mod tests {
         );
     }
 
+    #[async_std::test]
+    async fn test_handshake_async_hello_timeout() {
+        net(|mut stream_server, _, _stream_client, _| async move {
+            let net_id = auth::Key::from_slice(&hex::decode(NET_ID_HEX).unwrap()).unwrap();
+            let (server_pk, server_sk) = ed25519::gen_keypair();
+            let handshake = Handshake::new_server(net_id, server_pk, server_sk);
+
+            // The client connects and never sends the client hello
+            let start = std::time::Instant::now();
+            let res = handshake_server_timeout(
+                &mut stream_server,
+                handshake,
+                Duration::from_millis(100),
+                Duration::from_secs(60),
+            )
+            .await;
+            match res {
+                Err(Error::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::TimedOut),
+                res => panic!("unexpected result: {:?}", res.map(|_| ())),
+            }
+            assert!(start.elapsed() < Duration::from_secs(5));
+        })
+        .await;
+    }
+
+    #[async_std::test]
+    async fn test_handshake_async_timeout() {
+        net(|mut stream_server, _, mut stream_client, _| async move {
+            let net_id = auth::Key::from_slice(&hex::decode(NET_ID_HEX).unwrap()).unwrap();
+            let (client_pk, client_sk) = ed25519::gen_keypair();
+            let (server_pk, server_sk) = ed25519::gen_keypair();
+            let handshake = Handshake::new_server(net_id.clone(), server_pk, server_sk);
+
+            // The client sends the client hello and then stalls
+            let handshake_client = Handshake::new_client(net_id, client_pk, client_sk);
+            let mut buf = [0; handshake::CLIENT_HELLO_BYTES];
+            handshake_client.send_client_hello(&mut buf);
+            stream_client.write_all(&buf).await.unwrap();
+
+            let res = handshake_server_timeout(
+                &mut stream_server,
+                handshake,
+                Duration::from_secs(60),
+                Duration::from_millis(100),
+            )
+            .await;
+            match res {
+                Err(Error::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::TimedOut),
+                res => panic!("unexpected result: {:?}", res.map(|_| ())),
+            }
+        })
+        .await;
+    }
+
+    // Only use the `futures` io traits to make sure that no `async_std` specific trait is needed.
+    async fn handshake_futures_io<T>(stream_client: T, stream_server: T)
+    where
+        T: futures::io::AsyncRead + futures::io::AsyncWrite + Unpin,
+    {
+        handshake_aux(stream_client, stream_server).await
+    }
+
+    #[async_std::test]
+    async fn test_handshake_async_futures_io() {
+        net(|a, _, b, _| handshake_futures_io(a, b)).await;
+    }
+
+    #[async_std::test]
+    async fn test_handshake_async_pipelined() {
+        const EARLY: &[u8] = b"sent right after the client auth";
+        net(|mut stream_server, _, mut stream_client, _| async move {
+            let net_id = auth::Key::from_slice(&hex::decode(NET_ID_HEX).unwrap()).unwrap();
+            let (client_pk, client_sk) = ed25519::gen_keypair();
+            let (server_pk, server_sk) = ed25519::gen_keypair();
+
+            // The client sends the client auth and more bytes in a single write, without waiting
+            // for the server accept
+            let net_id_cpy = net_id.clone();
+            let client = async move {
+                let hs = Handshake::new_client(net_id_cpy, client_pk, client_sk);
+                let mut buf = [0; handshake::CLIENT_AUTH_BYTES + EARLY.len()];
+                let hs = hs.send_client_hello(&mut buf[..handshake::CLIENT_HELLO_BYTES]);
+                stream_client
+                    .write_all(&buf[..handshake::CLIENT_HELLO_BYTES])
+                    .await
+                    .unwrap();
+                let mut server_hello = [0; handshake::SERVER_HELLO_BYTES];
+                stream_client.read_exact(&mut server_hello).await.unwrap();
+                let hs = hs.recv_server_hello(&server_hello).unwrap();
+                let hs = hs
+                    .send_client_auth(&mut buf[..handshake::CLIENT_AUTH_BYTES], server_pk)
+                    .unwrap();
+                buf[handshake::CLIENT_AUTH_BYTES..].copy_from_slice(EARLY);
+                stream_client.write_all(&buf).await.unwrap();
+                let mut server_accept = [0; handshake::SERVER_ACCEPT_BYTES];
+                stream_client.read_exact(&mut server_accept).await.unwrap();
+                hs.recv_server_accept(&mut server_accept).unwrap();
+            };
+            let server = async {
+                handshake_server(&mut stream_server, net_id, server_pk, server_sk)
+                    .await
+                    .unwrap();
+                // The bytes sent ahead by the client are kept in the stream
+                let mut early = [0; EARLY.len()];
+                stream_server.read_exact(&mut early).await.unwrap();
+                assert_eq!(&early[..], EARLY);
+            };
+            client.join(server).await;
+        })
+        .await;
+    }
+
+    // Run a handshake with deterministic ephemeral keys, with either the client or the server
+    // driven by the sync implementation, and return the bytes of both `HandshakeComplete`.
+    #[cfg(feature = "sync")]
+    async fn handshake_sync_async(sync_client: bool) -> (Vec<u8>, Vec<u8>) {
+        use rand_chacha::ChaCha20Rng;
+        use rand_core::SeedableRng;
+
+        let net_id = auth::Key::from_slice(&hex::decode(NET_ID_HEX).unwrap()).unwrap();
+        let (client_pk, client_sk) = ed25519::keypair_from_seed(
+            &ed25519::Seed::from_slice(&hex::decode(CLIENT_SEED_HEX).unwrap()).unwrap(),
+        );
+        let (server_pk, server_sk) = ed25519::keypair_from_seed(
+            &ed25519::Seed::from_slice(&hex::decode(SERVER_SEED_HEX).unwrap()).unwrap(),
+        );
+        let hs_client = Handshake::new_client_rng(
+            net_id.clone(),
+            client_pk,
+            client_sk,
+            &mut ChaCha20Rng::from_seed([1; 32]),
+        );
+        let hs_server = Handshake::new_server_rng(
+            net_id,
+            server_pk,
+            server_sk,
+            &mut ChaCha20Rng::from_seed([2; 32]),
+        );
+
+        let (mut stream_sync, stream_async) = std::os::unix::net::UnixStream::pair().unwrap();
+        let mut stream_async = async_std::os::unix::net::UnixStream::from(stream_async);
+        let (client, server) = if sync_client {
+            let handle = std::thread::spawn(move || {
+                crate::sync::handshake_client_with(&mut stream_sync, hs_client, server_pk).unwrap()
+            });
+            let server = handshake_server_with(&mut stream_async, hs_server)
+                .await
+                .unwrap();
+            (handle.join().unwrap(), server)
+        } else {
+            let handle = std::thread::spawn(move || {
+                crate::sync::handshake_server_with(&mut stream_sync, hs_server).unwrap()
+            });
+            let client = handshake_client_with(&mut stream_async, hs_client, server_pk)
+                .await
+                .unwrap();
+            (client, handle.join().unwrap())
+        };
+        (client.to_bytes(), server.to_bytes())
+    }
+
+    #[cfg(feature = "sync")]
+    #[async_std::test]
+    async fn test_handshake_sync_async_interop() {
+        let (client_a, server_a) = handshake_sync_async(true).await;
+        let (client_b, server_b) = handshake_sync_async(false).await;
+        assert_eq!(client_a, client_b);
+        assert_eq!(server_a, server_b);
+    }
+
     #[async_std::test]
     async fn test_handshake_async() {
         net(|a, _, b, _| handshake_aux(a, b)).await;
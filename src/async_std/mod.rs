@@ -1,3 +1,5 @@
+//! Async handshake and box stream over any stream implementing the `futures` io traits (which
+//! `async_std` re-exports), so `smol` and other `futures` based executors work without `tokio`.
 mod boxstream;
 mod error;
 mod handshake;
@@ -9,7 +11,10 @@ mod circularbuffer;
 
 pub use boxstream::{BoxStream, BoxStreamRead, BoxStreamWrite};
 pub use error::{Error, Result};
-pub use handshake::{handshake_client, handshake_server};
+pub use handshake::{
+    handshake_client, handshake_client_with, handshake_server, handshake_server_timeout,
+    handshake_server_with,
+};
 
 #[cfg(feature = "tokio_compat")]
 pub use tokio_compat::{TokioCompat, TokioCompatExt, TokioCompatExtRead, TokioCompatExtWrite};
@@ -1,6 +1,6 @@
 extern crate log;
 
-use crate::handshake::HandshakeComplete;
+use crate::handshake::{fingerprint, net_id_bytes, HandshakeComplete};
 
 use core::{cmp, mem};
 use sodiumoxide::crypto::{auth, hash::sha256, scalarmult::curve25519, secretbox};
@@ -22,10 +22,18 @@ pub enum Error {
     DecryptHeaderSecretbox,
     #[error("secretbox::open failed in body decryption")]
     DecryptBodySecretbox,
+    /// No longer returned: decrypting after the goodbye returns `StreamClosed`.  Kept so that
+    /// existing matches on it still compile.
     #[error("received goodbye message")]
     GoodbyeReceived,
     #[error("sent goodbye message")]
     GoodbyeSent,
+    #[error("boxstream closed after a goodbye or a decryption error")]
+    StreamClosed,
+    #[error("message larger than the maximum boxstream message body")]
+    FrameTooLarge,
+    #[error("received header with a body larger than the maximum boxstream message body")]
+    HeaderBodyTooLarge,
 }
 
 impl convert::From<Error> for io::Error {
@@ -35,6 +43,9 @@ impl convert::From<Error> for io::Error {
             Error::DecryptBodySecretbox => Self::new(io::ErrorKind::InvalidData, error),
             Error::GoodbyeReceived => Self::new(io::ErrorKind::Other, error),
             Error::GoodbyeSent => Self::new(io::ErrorKind::Other, error),
+            Error::StreamClosed => Self::new(io::ErrorKind::Other, error),
+            Error::FrameTooLarge => Self::new(io::ErrorKind::InvalidInput, error),
+            Error::HeaderBodyTooLarge => Self::new(io::ErrorKind::InvalidData, error),
         }
     }
 }
@@ -58,6 +69,7 @@ impl KeyNonce {
     /// `(key_nonce_send, key_nonce_recv)`.
     pub fn from_handshake(handshake_complete: HandshakeComplete) -> (Self, Self) {
         let HandshakeComplete {
+            version: _,
             net_id,
             pk,
             ephemeral_pk,
@@ -67,7 +79,7 @@ impl KeyNonce {
         } = handshake_complete;
         let shared_secret_0 = sha256::hash(&concat!(
             auth::KEYBYTES + curve25519::GROUPELEMENTBYTES * 3,
-            net_id.as_ref(),
+            net_id_bytes(&net_id),
             shared_secret.ab.as_ref(),
             shared_secret.aB.as_ref(),
             shared_secret.Ab.as_ref()
@@ -101,6 +113,12 @@ impl KeyNonce {
             }
         }
     }
+
+    /// Returns a short fingerprint of the key, which can be logged by both peers to check that they
+    /// derived the same key for a direction without exposing it.
+    pub fn fingerprint(&self) -> String {
+        fingerprint(self.key.as_ref())
+    }
 }
 
 /// The header of a message in the boxstream.
@@ -195,7 +213,8 @@ impl BoxStreamSend {
     }
     /// Encrypt a single boxstream message by taking bytes from `buf` and encrypting them into
     /// `enc`.  Returns the number of bytes read from `buf` and the number of bytes written into
-    /// `enc`.
+    /// `enc`.  At most `MSG_BODY_MAX_LEN` bytes are taken from `buf`, so a longer `buf` must be
+    /// sent in several messages by calling `encrypt` again with the remaining bytes.
     pub fn encrypt(&mut self, buf: &[u8], mut enc: &mut [u8]) -> Result<(usize, usize)> {
         if self.goodbye {
             return Err(Error::GoodbyeSent);
@@ -206,6 +225,16 @@ impl BoxStreamSend {
             Ok(encrypt_box_stream_msg(&mut self.key_nonce, buf, &mut enc))
         }
     }
+    /// Encrypt all of `buf` into a single boxstream message in `enc`, failing with
+    /// `Error::FrameTooLarge` if `buf` is longer than `MSG_BODY_MAX_LEN` instead of splitting it
+    /// like [`BoxStreamSend::encrypt`].  Returns the number of bytes written into `enc`.
+    pub fn encrypt_exact(&mut self, buf: &[u8], enc: &mut [u8]) -> Result<usize> {
+        if buf.len() > MSG_BODY_MAX_LEN {
+            return Err(Error::FrameTooLarge);
+        }
+        let (_, n) = self.encrypt(buf, enc)?;
+        Ok(n)
+    }
     /// Encrypt a goodbye message into `enc`.  Returns the number of bytes written into `enc`.
     pub fn encrypt_goodbye(&mut self, enc: &mut [u8]) -> Result<usize> {
         if self.goodbye {
@@ -260,9 +289,13 @@ fn decrypt_box_stream_header(
             if header_body_buf.iter().all(|&b| b == 0) {
                 Ok(Decrypted::Goodbye)
             } else {
-                Ok(Decrypted::Some(
-                    Header::from_slice(&header_body_buf).unwrap(),
-                ))
+                let header = Header::from_slice(&header_body_buf).unwrap();
+                // A peer can claim any body length up to u16::MAX, but never sends more than
+                // `MSG_BODY_MAX_LEN`, which is what receivers allocate for the body.
+                if header.body_len > MSG_BODY_MAX_LEN {
+                    return Err(Error::HeaderBodyTooLarge);
+                }
+                Ok(Decrypted::Some(header))
             }
         }
         Err(()) => Err(Error::DecryptHeaderSecretbox),
@@ -304,6 +337,7 @@ pub struct BoxStreamRecv {
     key_nonce: KeyNonce,
     state: RecvState,
     goodbye: bool,
+    poisoned: bool,
 }
 
 impl BoxStreamRecv {
@@ -313,18 +347,28 @@ impl BoxStreamRecv {
             key_nonce,
             state: RecvState::ExpectHeader,
             goodbye: false,
+            poisoned: false,
         }
     }
 
     /// Decrypt a single boxstream message every two calls (one to decrypt and parse the header, the
     /// other do decrypt the body) by decrypting from `buf` and writting the plaintex into `dec`.
     /// Returns the number of bytes read from `buf` and the number of bytes written into `dec`.  If
-    /// a goodbye message is received, an Err(Error::Goodbye) will be returned and the following
-    /// calls will return Ok((0, 0)).
+    /// a goodbye message is received, `Ok(Decrypted::Goodbye)` will be returned.  If the
+    /// decryption fails, the boxstream can't be recovered.  In both cases the boxstream is closed
+    /// and the following calls will return `Err(Error::StreamClosed)`.
     pub fn decrypt(&mut self, buf: &[u8], dec: &mut [u8]) -> Result<Decrypted<(usize, usize)>> {
-        if self.goodbye {
-            return Err(Error::GoodbyeReceived);
+        if self.poisoned {
+            return Err(Error::StreamClosed);
         }
+        let decrypted = self.decrypt_next(buf, dec);
+        if decrypted.is_err() || self.goodbye {
+            self.poisoned = true;
+        }
+        decrypted
+    }
+
+    fn decrypt_next(&mut self, buf: &[u8], dec: &mut [u8]) -> Result<Decrypted<(usize, usize)>> {
         let n = self.recv_bytes();
         let mut state = RecvState::ExpectHeader;
         mem::swap(&mut state, &mut self.state);
@@ -356,7 +400,7 @@ impl BoxStreamRecv {
 
     /// Returns the number of received bytes needed for the next `decrypt` call.
     pub fn recv_bytes(&self) -> usize {
-        if !self.goodbye {
+        if !self.poisoned {
             match &self.state {
                 RecvState::ExpectHeader => MSG_HEADER_LEN,
                 RecvState::ExpectBody(header) => header.body_len,
@@ -370,6 +414,12 @@ impl BoxStreamRecv {
     pub fn goodbye_recvd(&self) -> bool {
         self.goodbye
     }
+
+    /// Returns whether the boxstream is closed, after receiving the goodbye or after a decryption
+    /// error.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
 }
 
 #[cfg(test)]
@@ -403,6 +453,8 @@ mod tests {
         };
         test_error(Error::DecryptBodySecretbox);
         test_error(Error::DecryptHeaderSecretbox);
+        test_error(Error::StreamClosed);
+        test_error(Error::FrameTooLarge);
     }
 
     #[test]
@@ -555,16 +607,140 @@ mod tests {
             sender.encrypt(&[0, 1, 2, 3], &mut buf_a)
         );
         assert_eq!(
-            Err(Error::GoodbyeReceived),
+            Err(Error::StreamClosed),
             receiver.decrypt(&recv_buf_a, &mut buf_b)
         );
     }
 
+    #[test]
+    fn test_boxstream_nonce_sequence() {
+        let (peer_a, peer_b) = load_peers();
+        let mut sender = BoxStreamSend::new(peer_a.key_nonce_send);
+        let mut buf = [0; 4096];
+
+        // Each message uses one nonce for the header and the next one for the body
+        let expected_nonces = [
+            "a20fa8fe59a80f5f07c80265e5e7664582f0f553f36cd6d0",
+            "a20fa8fe59a80f5f07c80265e5e7664582f0f553f36cd6d2",
+            "a20fa8fe59a80f5f07c80265e5e7664582f0f553f36cd6d4",
+        ];
+        let mut frames = Vec::new();
+        for (i, expected_nonce) in expected_nonces.iter().enumerate() {
+            let (_, n) = sender.encrypt(&[i as u8; 16], &mut buf).unwrap();
+            frames.push(buf[..n].to_vec());
+            assert_eq!(&hex::encode(sender.key_nonce.nonce), expected_nonce);
+        }
+
+        // Frames decrypt in order
+        let mut receiver = BoxStreamRecv::new(KeyNonce {
+            key: peer_b.key_nonce_recv.key.clone(),
+            nonce: peer_b.key_nonce_recv.nonce,
+        });
+        for (i, frame) in frames.iter().enumerate() {
+            receiver.decrypt(frame, &mut buf).unwrap();
+            let (_, n) = receiver
+                .decrypt(&frame[MSG_HEADER_LEN..], &mut buf)
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf[..n], &[i as u8; 16]);
+        }
+        assert_eq!(
+            &hex::encode(receiver.key_nonce.nonce),
+            expected_nonces[expected_nonces.len() - 1]
+        );
+
+        // A frame decrypted with the wrong nonce fails
+        let mut receiver = BoxStreamRecv::new(peer_b.key_nonce_recv);
+        assert_eq!(
+            Err(Error::DecryptHeaderSecretbox),
+            receiver.decrypt(&frames[1], &mut buf)
+        );
+    }
+
+    #[test]
+    fn test_boxstream_encrypt_max_len() {
+        let (peer_a, _) = load_peers();
+        let mut sender = BoxStreamSend::new(peer_a.key_nonce_send);
+        let msg = [7; 5000];
+        let mut enc = [0; MSG_HEADER_LEN + MSG_BODY_MAX_LEN];
+
+        // `encrypt` splits the message in two
+        let (n, m) = sender.encrypt(&msg, &mut enc).unwrap();
+        assert_eq!(
+            (n, m),
+            (MSG_BODY_MAX_LEN, MSG_HEADER_LEN + MSG_BODY_MAX_LEN)
+        );
+        let (n, m) = sender.encrypt(&msg[n..], &mut enc).unwrap();
+        assert_eq!((n, m), (5000 - MSG_BODY_MAX_LEN, MSG_HEADER_LEN + n));
+
+        // `encrypt_exact` rejects it
+        assert_eq!(
+            Err(Error::FrameTooLarge),
+            sender.encrypt_exact(&msg, &mut enc)
+        );
+        assert_eq!(
+            Ok(MSG_HEADER_LEN + MSG_BODY_MAX_LEN),
+            sender.encrypt_exact(&msg[..MSG_BODY_MAX_LEN], &mut enc)
+        );
+    }
+
+    #[test]
+    fn test_boxstream_recv_poisoned() {
+        let (peer_a, peer_b) = load_peers();
+        let mut sender = BoxStreamSend::new(peer_a.key_nonce_send);
+        let mut receiver = BoxStreamRecv::new(peer_b.key_nonce_recv);
+        let mut buf_a = [0; 4096];
+        let mut buf_b = [0; 4096];
+
+        // Corrupt the body of a message
+        let (_, n) = sender.encrypt(&[1, 2, 3, 4], &mut buf_a).unwrap();
+        buf_a[n - 1] ^= 0xff;
+        receiver.decrypt(&buf_a, &mut buf_b).unwrap();
+        assert_eq!(
+            Err(Error::DecryptBodySecretbox),
+            receiver.decrypt(&buf_a[MSG_HEADER_LEN..n], &mut buf_b)
+        );
+        assert!(receiver.is_poisoned());
+        assert_eq!(0, receiver.recv_bytes());
+
+        // The following valid message is not decrypted
+        let (_, n) = sender.encrypt(&[1, 2, 3, 4], &mut buf_a).unwrap();
+        assert_eq!(
+            Err(Error::StreamClosed),
+            receiver.decrypt(&buf_a[..n], &mut buf_b)
+        );
+    }
+
+    #[test]
+    fn test_boxstream_recv_header_too_large() {
+        let (peer_a, peer_b) = load_peers();
+        let mut receiver = BoxStreamRecv::new(peer_b.key_nonce_recv);
+        let mut buf = [0; MSG_HEADER_LEN];
+
+        // A header claiming a body longer than any valid message
+        let mut header = Header {
+            body_len: MSG_BODY_MAX_LEN + 1,
+            body_mac: secretbox::Tag([0; secretbox::MACBYTES]),
+        }
+        .to_bytes();
+        let key_nonce = peer_a.key_nonce_send;
+        let tag = secretbox::seal_detached(&mut header, &key_nonce.nonce, &key_nonce.key);
+        buf[..secretbox::MACBYTES].copy_from_slice(tag.as_ref());
+        buf[secretbox::MACBYTES..].copy_from_slice(&header);
+
+        let mut dec = [0; MSG_HEADER_LEN];
+        assert_eq!(
+            Err(Error::HeaderBodyTooLarge),
+            receiver.decrypt(&buf, &mut dec)
+        );
+        assert!(receiver.is_poisoned());
+    }
+
     use crate::handshake;
     use sodiumoxide::crypto::{auth, sign::ed25519};
 
-    #[test]
-    fn test_key_nonce() {
+    // Run a handshake between a client and a server, returning both `HandshakeComplete`.
+    fn handshake_complete() -> (HandshakeComplete, HandshakeComplete) {
         // Copied from `src/handshake.rs`:`fn test_handshake()`
         let net_id_hex = "d4a1cb88a66f02f8db635ce26441cc5dac1b08420ceaac230839b755845a9ffb";
         let net_id = auth::Key::from_slice(&hex::decode(net_id_hex).unwrap()).unwrap();
@@ -611,12 +787,51 @@ mod tests {
             (hs_client, hs_server)
         };
 
-        let complete_client = hs_client.complete();
-        let complete_server = hs_server.complete();
+        (hs_client.complete(), hs_server.complete())
+    }
+
+    #[test]
+    fn test_key_nonce() {
+        let (complete_client, complete_server) = handshake_complete();
 
         let (client_send, client_recv) = KeyNonce::from_handshake(complete_client);
         let (server_send, server_recv) = KeyNonce::from_handshake(complete_server);
         assert_eq!(client_send, server_recv);
         assert_eq!(client_recv, server_send);
     }
+
+    #[test]
+    fn test_key_nonce_fingerprint() {
+        let (complete_client, complete_server) = handshake_complete();
+        let (client_send, client_recv) = KeyNonce::from_handshake(complete_client);
+        let (server_send, server_recv) = KeyNonce::from_handshake(complete_server);
+
+        assert_eq!(16, client_send.fingerprint().len());
+        assert_eq!(client_send.fingerprint(), server_recv.fingerprint());
+        assert_eq!(client_recv.fingerprint(), server_send.fingerprint());
+        assert_ne!(client_send.fingerprint(), client_recv.fingerprint());
+        assert!(!hex::encode(&client_send.key).contains(&client_send.fingerprint()));
+    }
+
+    #[test]
+    fn test_key_nonce_separation() {
+        let (complete_client, _) = handshake_complete();
+
+        // Each direction must use its own key and its own nonce
+        let (send, recv) = KeyNonce::from_handshake(complete_client);
+        assert_ne!(send.key, recv.key);
+        assert_ne!(send.nonce, recv.nonce);
+        let secrets: Vec<&[u8]> = vec![
+            send.key.as_ref(),
+            send.nonce.as_ref(),
+            recv.key.as_ref(),
+            recv.nonce.as_ref(),
+        ];
+        for (i, a) in secrets.iter().enumerate() {
+            for b in &secrets[i + 1..] {
+                let n = cmp::min(a.len(), b.len());
+                assert_ne!(a[..n], b[..n]);
+            }
+        }
+    }
 }
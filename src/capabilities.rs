@@ -0,0 +1,46 @@
+use crate::handshake::HandshakeVersion;
+
+/// Version of the box stream protocol implemented by this crate.
+pub const BOXSTREAM_VERSION: u8 = 1;
+
+/// The protocol versions implemented by this crate and the optional APIs that have been compiled
+/// in through features.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capabilities {
+    /// Secret handshake protocol version.
+    pub handshake_version: HandshakeVersion,
+    /// Box stream protocol version.
+    pub boxstream_version: u8,
+    /// Synchronous API, enabled by the `sync` feature.
+    pub sync: bool,
+    /// Asynchronous `async_std` API, enabled by the `async_std` feature.
+    pub async_std: bool,
+    /// `tokio` wrappers for the `async_std` API, enabled by the `tokio_compat` feature.
+    pub tokio_compat: bool,
+}
+
+/// Returns the `Capabilities` of this build of the crate.
+pub fn capabilities() -> Capabilities {
+    Capabilities {
+        handshake_version: HandshakeVersion::default(),
+        boxstream_version: BOXSTREAM_VERSION,
+        sync: cfg!(feature = "sync"),
+        async_std: cfg!(feature = "async_std"),
+        tokio_compat: cfg!(feature = "tokio_compat"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities() {
+        let caps = capabilities();
+        assert_eq!(caps.handshake_version, HandshakeVersion::V1);
+        assert_eq!(caps.boxstream_version, 1);
+        assert_eq!(caps.sync, cfg!(feature = "sync"));
+        assert_eq!(caps.async_std, cfg!(feature = "async_std"));
+        assert_eq!(caps.tokio_compat, cfg!(feature = "tokio_compat"));
+    }
+}
@@ -1,3 +1,5 @@
+use core::fmt;
+use rand_core::{CryptoRng, RngCore};
 use sodiumoxide::crypto::{auth, hash::sha256, scalarmult::curve25519, secretbox, sign::ed25519};
 use thiserror::Error;
 
@@ -40,11 +42,26 @@ pub enum Error {
     RecvClientHelloScalarmult(ScalarMultSk, ScalarMultPk),
     #[error("scalar math failed (recvclientauth)")]
     RecvClientAuthScalarmult(ScalarMultSk, ScalarMultPk),
+    #[error("client public key is the server public key")]
+    SelfConnection,
+    #[error("invalid handshake complete encoding")]
+    InvalidHandshakeComplete,
+    #[error("crypto backend failed to initialize")]
+    CryptoUnavailable,
 }
 
 /// The result type for handshake operations.
 pub type Result<T> = core::result::Result<T, Error>;
 
+/// The error type for [`validate_config`].
+#[derive(Error, Debug, PartialEq)]
+pub enum ConfigError {
+    #[error("secret key doesn't correspond to the public key")]
+    KeypairMismatch,
+    #[error("server public key is not a valid key")]
+    InvalidServerKey,
+}
+
 #[derive(Debug)]
 #[allow(non_snake_case)]
 pub struct SharedSecretPartial {
@@ -52,6 +69,31 @@ pub struct SharedSecretPartial {
     aB: curve25519::GroupElement,
 }
 
+/// The version of the secret handshake protocol.  Only version 1 is currently implemented, so
+/// both peers always agree on it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HandshakeVersion {
+    #[default]
+    V1,
+}
+
+impl HandshakeVersion {
+    /// The byte that identifies this version, as used in [`HandshakeComplete::to_bytes`].
+    pub const fn to_u8(self) -> u8 {
+        match self {
+            HandshakeVersion::V1 => 1,
+        }
+    }
+
+    /// The version identified by the byte `version`, or `None` if it is not implemented.
+    pub const fn from_u8(version: u8) -> Option<Self> {
+        match version {
+            1 => Some(HandshakeVersion::V1),
+            _ => None,
+        }
+    }
+}
+
 /// The shared secret obtained after a successful handshake.
 #[derive(Debug, Clone, PartialEq)]
 #[allow(non_snake_case)]
@@ -61,20 +103,180 @@ pub struct SharedSecret {
     pub Ab: curve25519::GroupElement,
 }
 
+/// Compute the three Diffie-Hellman results of the handshake the way the client does, from the
+/// client ephemeral and long-term secret keys and the server ephemeral and long-term public keys.
+///
+/// This is only meant for testing and interoperability, to cross-check each intermediate scalar
+/// multiplication against reference vectors.  The handshake computes the same values step by step,
+/// which the tests check by comparing them with the shared secret of a real handshake.
+#[cfg(any(test, feature = "interop"))]
+pub fn compute_shared_secrets(
+    client_ephemeral_sk: &curve25519::Scalar,
+    server_ephemeral_pk: &curve25519::GroupElement,
+    client_sk: &ed25519::SecretKey,
+    server_pk: &ed25519::PublicKey,
+) -> Result<SharedSecret> {
+    let fn_error = |a, b| Err(Error::SendClientAuthScalarmult(a, b));
+    Ok(SharedSecret {
+        ab: curve25519::scalarmult(client_ephemeral_sk, server_ephemeral_pk)
+            .or_else(|_| fn_error(ScalarMultSk::Ephemeral, ScalarMultPk::ServerEphemeral))?,
+        aB: curve25519::scalarmult(client_ephemeral_sk, &server_pk.to_curve25519())
+            .or_else(|_| fn_error(ScalarMultSk::Ephemeral, ScalarMultPk::ServerLongTerm))?,
+        Ab: curve25519::scalarmult(&client_sk.to_curve25519(), server_ephemeral_pk)
+            .or_else(|_| fn_error(ScalarMultSk::LongTerm, ScalarMultPk::ServerEphemeral))?,
+    })
+}
+
+/// The long-term secret key of a peer, used to sign its handshake auth message and in one of the
+/// scalar multiplications of the handshake.  Implementing it allows keeping the secret key behind
+/// an interface that never exposes it, like a keystore or an HSM.  It is implemented for
+/// `ed25519::SecretKey`.
+pub trait Signer {
+    /// Returns the public key of the secret key.
+    fn public_key(&self) -> ed25519::PublicKey;
+    /// Sign `msg` with the secret key.
+    fn sign_detached(&self, msg: &[u8]) -> ed25519::Signature;
+    /// Multiply the curve25519 conversion of the secret key with `pk`.
+    fn scalarmult(
+        &self,
+        pk: &curve25519::GroupElement,
+    ) -> core::result::Result<curve25519::GroupElement, SignerError>;
+}
+
+/// The error type for [`Signer`] operations.  `Unavailable` is meant for signers backed by a
+/// keystore or an HSM that can't be reached.
+#[derive(Error, Debug, PartialEq)]
+pub enum SignerError {
+    #[error("scalar multiplication resulted in the identity element")]
+    ScalarMult,
+    #[error("signer unavailable: {0}")]
+    Unavailable(String),
+}
+
+impl Signer for ed25519::SecretKey {
+    fn public_key(&self) -> ed25519::PublicKey {
+        ed25519::SecretKey::public_key(self)
+    }
+    fn sign_detached(&self, msg: &[u8]) -> ed25519::Signature {
+        ed25519::sign_detached(msg, self)
+    }
+    fn scalarmult(
+        &self,
+        pk: &curve25519::GroupElement,
+    ) -> core::result::Result<curve25519::GroupElement, SignerError> {
+        curve25519::scalarmult(&self.to_curve25519(), pk).map_err(|_| SignerError::ScalarMult)
+    }
+}
+
 /// The initialization data of a handshake that exists in every state of the handshake.
-#[derive(Debug)]
 pub struct HandshakeBase {
     net_id: auth::Key,
     pk: ed25519::PublicKey,
-    sk: ed25519::SecretKey,
+    signer: Box<dyn Signer + Send + Sync>,
     ephemeral_pk: curve25519::GroupElement,
     ephemeral_sk: curve25519::Scalar,
+    version: HandshakeVersion,
+    reject_self_connection: bool,
+}
+
+// The signer is left out, since it is only known to implement `Signer`.
+impl fmt::Debug for HandshakeBase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HandshakeBase")
+            .field("net_id", &self.net_id)
+            .field("pk", &self.pk)
+            .field("ephemeral_pk", &self.ephemeral_pk)
+            .field("ephemeral_sk", &self.ephemeral_sk)
+            .field("version", &self.version)
+            .field("reject_self_connection", &self.reject_self_connection)
+            .finish_non_exhaustive()
+    }
+}
+
+impl HandshakeBase {
+    /// Create a `HandshakeBase` taking the ephemeral keys from the ed25519 `ephemeral_keypair`.
+    fn new(
+        net_id: auth::Key,
+        pk: ed25519::PublicKey,
+        signer: Box<dyn Signer + Send + Sync>,
+        ephemeral_keypair: (ed25519::PublicKey, ed25519::SecretKey),
+    ) -> Self {
+        crate::init::check_initialized();
+        let (ephemeral_ed_pk, ephemeral_ed_sk) = ephemeral_keypair;
+        HandshakeBase {
+            net_id,
+            pk,
+            signer,
+            ephemeral_pk: ephemeral_ed_pk.to_curve25519(),
+            ephemeral_sk: ephemeral_ed_sk.to_curve25519(),
+            version: HandshakeVersion::default(),
+            reject_self_connection: false,
+        }
+    }
+}
+
+/// Returns the raw bytes of the network identifier `net_id`, which are hashed in the handshake and
+/// in the boxstream key derivation.
+pub fn net_id_bytes(net_id: &auth::Key) -> &[u8; auth::KEYBYTES] {
+    &net_id.0
+}
+
+/// Returns a short fingerprint of `bytes`: the hex of the first 8 bytes of their sha256 hash.
+pub(crate) fn fingerprint(bytes: &[u8]) -> String {
+    hex::encode(&sha256::hash(bytes)[..8])
+}
+
+/// Validate the key material of a handshake without performing it: check that `sk` is the secret
+/// key of `pk` and that `server_pk` (when given, for a client) can be used in the handshake scalar
+/// multiplications.  The `net_id` is any 32 byte key, so it is always valid.
+pub fn validate_config(
+    _net_id: &auth::Key,
+    pk: &ed25519::PublicKey,
+    sk: &ed25519::SecretKey,
+    server_pk: Option<&ed25519::PublicKey>,
+) -> core::result::Result<(), ConfigError> {
+    // The secret key stores the seed followed by the public key, and signing uses the stored
+    // public key, so the whole keypair derived from the seed must match
+    let mut seed = ed25519::Seed([0; ed25519::SEEDBYTES]);
+    seed.0.copy_from_slice(&sk.0[..ed25519::SEEDBYTES]);
+    if ed25519::keypair_from_seed(&seed) != (*pk, sk.clone()) {
+        return Err(ConfigError::KeypairMismatch);
+    }
+    if let Some(server_pk) = server_pk {
+        if curve25519::scalarmult(&sk.to_curve25519(), &server_pk.to_curve25519()).is_err() {
+            return Err(ConfigError::InvalidServerKey);
+        }
+    }
+    Ok(())
+}
+
+// Apply a non standard application version to the `net_id`, see `with_app_version`.
+fn net_id_app_version(net_id: &auth::Key, app_version: &[u8]) -> auth::Key {
+    if app_version.is_empty() {
+        net_id.clone()
+    } else {
+        auth::Key(auth::authenticate(app_version, net_id).0)
+    }
+}
+
+/// Generate an ed25519 keypair from a seed drawn from `rng`.
+fn gen_keypair_rng<R: RngCore + CryptoRng>(
+    rng: &mut R,
+) -> (ed25519::PublicKey, ed25519::SecretKey) {
+    let mut seed = ed25519::Seed([0; ed25519::SEEDBYTES]);
+    rng.fill_bytes(&mut seed.0);
+    ed25519::keypair_from_seed(&seed)
 }
 
 /// The `Handshake` type maintains the different states that happen in each step of the handshake,
 /// allowing it to advance to completion.
 ///
 /// The `Handshake` follows the [typestate pattern](http://cliffle.com/blog/rust-typestate/).
+///
+/// Each message is bound to the messages exchanged before it: the client auth and the server accept
+/// are sealed with keys derived from both ephemeral keys of the session, and their signatures cover
+/// the hash of the ephemeral shared secret.  Replacing any earlier message, even with a valid one
+/// from another session, makes the following client auth or server accept fail.
 #[derive(Debug)]
 pub struct Handshake<S: State> {
     pub base: HandshakeBase,
@@ -159,40 +361,120 @@ pub struct Complete {
 /// - [`SendServerHello`] - `send_server_hello()` -> [`RecvClientAuth`]
 /// - [`RecvClientAuth`] - `recv_client_auth()` -> [`SendServerAccept`]
 /// - [`SendServerAccept`] - `send_server_accept()` -> [`Complete`]
-pub trait State {}
-impl State for SendClientHello {}
-impl State for RecvServerHello {}
-impl State for SendClientAuth {}
-impl State for RecvServerAccept {}
+pub trait State {
+    /// The `HandshakeStep` of this state.
+    const STEP: HandshakeStep;
+}
+impl State for SendClientHello {
+    const STEP: HandshakeStep = HandshakeStep::SendClientHello;
+}
+impl State for RecvServerHello {
+    const STEP: HandshakeStep = HandshakeStep::RecvServerHello;
+}
+impl State for SendClientAuth {
+    const STEP: HandshakeStep = HandshakeStep::SendClientAuth;
+}
+impl State for RecvServerAccept {
+    const STEP: HandshakeStep = HandshakeStep::RecvServerAccept;
+}
+
+impl State for RecvClientHello {
+    const STEP: HandshakeStep = HandshakeStep::RecvClientHello;
+}
+impl State for SendServerHello {
+    const STEP: HandshakeStep = HandshakeStep::SendServerHello;
+}
+impl State for RecvClientAuth {
+    const STEP: HandshakeStep = HandshakeStep::RecvClientAuth;
+}
+impl State for SendServerAccept {
+    const STEP: HandshakeStep = HandshakeStep::SendServerAccept;
+}
+
+impl State for Complete {
+    const STEP: HandshakeStep = HandshakeStep::Complete;
+}
 
-impl State for RecvClientHello {}
-impl State for SendServerHello {}
-impl State for RecvClientAuth {}
-impl State for SendServerAccept {}
+/// The step of the handshake a `Handshake` is in, named after its [`State`].  Useful for logging
+/// and error reporting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeStep {
+    SendClientHello,
+    RecvServerHello,
+    SendClientAuth,
+    RecvServerAccept,
+    RecvClientHello,
+    SendServerHello,
+    RecvClientAuth,
+    SendServerAccept,
+    Complete,
+}
 
-impl State for Complete {}
+impl<S: State> Handshake<S> {
+    /// Returns the current step of the handshake.
+    pub fn step(&self) -> HandshakeStep {
+        S::STEP
+    }
+}
 
 // Client
 impl Handshake<SendClientHello> {
     /// Create a new handshake client that can send the client hello.
+    ///
+    /// This generates the ephemeral keys without checking that the crypto backend is
+    /// available, so [`init`](crate::init()) must have succeeded before.
     pub fn new_client(
         net_id: auth::Key,
         pk: ed25519::PublicKey,
         sk: ed25519::SecretKey,
     ) -> Handshake<SendClientHello> {
-        let (ephemeral_ed_pk, ephemeral_ed_sk) = ed25519::gen_keypair();
-        let ephemeral_pk = ephemeral_ed_pk.to_curve25519();
-        let ephemeral_sk = ephemeral_ed_sk.to_curve25519();
+        let base = HandshakeBase::new(net_id, pk, Box::new(sk), ed25519::gen_keypair());
+        let state = SendClientHello;
+        Handshake { base, state }
+    }
+
+    /// Create a new handshake client that can send the client hello, drawing the ephemeral keys
+    /// from the cryptographically secure `rng` instead of the global one.
+    pub fn new_client_rng<R: RngCore + CryptoRng>(
+        net_id: auth::Key,
+        pk: ed25519::PublicKey,
+        sk: ed25519::SecretKey,
+        rng: &mut R,
+    ) -> Handshake<SendClientHello> {
+        let base = HandshakeBase::new(net_id, pk, Box::new(sk), gen_keypair_rng(rng));
         let state = SendClientHello;
-        let base = HandshakeBase {
-            net_id,
-            pk,
-            sk,
-            ephemeral_pk,
-            ephemeral_sk,
-        };
         Handshake { base, state }
     }
+
+    /// Create a new handshake client that can send the client hello, using `signer` instead of a
+    /// secret key.
+    pub fn new_client_signer<T: Signer + Send + Sync + 'static>(
+        net_id: auth::Key,
+        signer: T,
+    ) -> Handshake<SendClientHello> {
+        let pk = signer.public_key();
+        let base = HandshakeBase::new(net_id, pk, Box::new(signer), ed25519::gen_keypair());
+        let state = SendClientHello;
+        Handshake { base, state }
+    }
+
+    /// Set the `version` of the handshake protocol to use.
+    pub fn with_version(mut self, version: HandshakeVersion) -> Self {
+        self.base.version = version;
+        self
+    }
+
+    /// Mix the non standard application version `app_version` into the net_id, so that the
+    /// handshake only succeeds with peers that use the same net_id and `app_version`.  The
+    /// net_id of the `HandshakeComplete` will be the mixed one, from which the boxstream keys are
+    /// derived.  An empty `app_version` keeps the standard behavior.
+    ///
+    /// This is an extension for private or experimental networks, and is not part of the secret
+    /// handshake protocol.
+    pub fn with_app_version(mut self, app_version: &[u8]) -> Self {
+        self.base.net_id = net_id_app_version(&self.base.net_id, app_version);
+        self
+    }
 }
 
 /// Size of the client hello message.
@@ -259,22 +541,19 @@ impl Handshake<SendClientAuth> {
                 .or_else(|_| fn_error(ScalarMultSk::Ephemeral, ScalarMultPk::ServerEphemeral))?,
             aB: curve25519::scalarmult(&self.base.ephemeral_sk, &server_pk.to_curve25519())
                 .or_else(|_| fn_error(ScalarMultSk::Ephemeral, ScalarMultPk::ServerLongTerm))?,
-            Ab: curve25519::scalarmult(
-                &self.base.sk.to_curve25519(),
-                &self.state.server_ephemeral_pk,
-            )
-            .or_else(|_| fn_error(ScalarMultSk::LongTerm, ScalarMultPk::ServerEphemeral))?,
+            Ab: self
+                .base
+                .signer
+                .scalarmult(&self.state.server_ephemeral_pk)
+                .or_else(|_| fn_error(ScalarMultSk::LongTerm, ScalarMultPk::ServerEphemeral))?,
         };
 
-        let sig = ed25519::sign_detached(
-            &concat!(
-                auth::KEYBYTES + ed25519::PUBLICKEYBYTES + sha256::DIGESTBYTES,
-                self.base.net_id.as_ref(),
-                server_pk.as_ref(),
-                sha256::hash(shared_secret.ab.as_ref()).as_ref()
-            ),
-            &self.base.sk,
-        );
+        let sig = self.base.signer.sign_detached(&concat!(
+            auth::KEYBYTES + ed25519::PUBLICKEYBYTES + sha256::DIGESTBYTES,
+            net_id_bytes(&self.base.net_id),
+            server_pk.as_ref(),
+            sha256::hash(shared_secret.ab.as_ref()).as_ref()
+        ));
 
         let tag = secretbox::seal_detached(
             concat_into!(
@@ -286,7 +565,7 @@ impl Handshake<SendClientAuth> {
             &secretbox::Key(
                 sha256::hash(&concat!(
                     auth::KEYBYTES + curve25519::GROUPELEMENTBYTES * 2,
-                    self.base.net_id.as_ref(),
+                    net_id_bytes(&self.base.net_id),
                     shared_secret.ab.as_ref(),
                     shared_secret.aB.as_ref()
                 ))
@@ -321,7 +600,7 @@ impl Handshake<RecvServerAccept> {
             &secretbox::Key(
                 sha256::hash(
                     &[
-                        self.base.net_id.as_ref(),
+                        net_id_bytes(&self.base.net_id),
                         self.state.shared_secret.ab.as_ref(),
                         self.state.shared_secret.aB.as_ref(),
                         self.state.shared_secret.Ab.as_ref(),
@@ -337,7 +616,7 @@ impl Handshake<RecvServerAccept> {
         if !ed25519::verify_detached(
             &sig,
             &[
-                self.base.net_id.as_ref(),
+                net_id_bytes(&self.base.net_id),
                 self.state.sig.as_ref(),
                 self.base.pk.as_ref(),
                 sha256::hash(self.state.shared_secret.ab.as_ref()).as_ref(),
@@ -365,28 +644,68 @@ impl Handshake<RecvServerAccept> {
 // Server
 impl Handshake<RecvClientHello> {
     /// Create a new handshake server that can receive the client hello.
+    ///
+    /// Like in [`Handshake::new_client`], [`init`](crate::init()) must have succeeded before.
     pub fn new_server(
         net_id: auth::Key,
         pk: ed25519::PublicKey,
         sk: ed25519::SecretKey,
     ) -> Handshake<RecvClientHello> {
-        let (ephemeral_ed_pk, ephemeral_ed_sk) = ed25519::gen_keypair();
-        let ephemeral_pk = ephemeral_ed_pk.to_curve25519();
-        let ephemeral_sk = ephemeral_ed_sk.to_curve25519();
         Handshake {
-            base: HandshakeBase {
-                net_id,
-                pk,
-                sk,
-                ephemeral_pk,
-                ephemeral_sk,
-            },
+            base: HandshakeBase::new(net_id, pk, Box::new(sk), ed25519::gen_keypair()),
+            state: RecvClientHello,
+        }
+    }
+
+    /// Create a new handshake server that can receive the client hello, drawing the ephemeral
+    /// keys from the cryptographically secure `rng` instead of the global one.
+    pub fn new_server_rng<R: RngCore + CryptoRng>(
+        net_id: auth::Key,
+        pk: ed25519::PublicKey,
+        sk: ed25519::SecretKey,
+        rng: &mut R,
+    ) -> Handshake<RecvClientHello> {
+        Handshake {
+            base: HandshakeBase::new(net_id, pk, Box::new(sk), gen_keypair_rng(rng)),
+            state: RecvClientHello,
+        }
+    }
+
+    /// Create a new handshake server that can receive the client hello, using `signer` instead of
+    /// a secret key.
+    pub fn new_server_signer<T: Signer + Send + Sync + 'static>(
+        net_id: auth::Key,
+        signer: T,
+    ) -> Handshake<RecvClientHello> {
+        let pk = signer.public_key();
+        Handshake {
+            base: HandshakeBase::new(net_id, pk, Box::new(signer), ed25519::gen_keypair()),
             state: RecvClientHello,
         }
     }
 }
 
 impl Handshake<RecvClientHello> {
+    /// Set the `version` of the handshake protocol to use.
+    pub fn with_version(mut self, version: HandshakeVersion) -> Self {
+        self.base.version = version;
+        self
+    }
+
+    /// Mix the non standard application version `app_version` into the net_id, like the client
+    /// `with_app_version` does.  Clients must use the same `app_version`.
+    pub fn with_app_version(mut self, app_version: &[u8]) -> Self {
+        self.base.net_id = net_id_app_version(&self.base.net_id, app_version);
+        self
+    }
+
+    /// Make the server fail the handshake with `Error::SelfConnection` when the authenticated
+    /// client public key is the server's own public key.
+    pub fn reject_self_connection(mut self) -> Self {
+        self.base.reject_self_connection = true;
+        self
+    }
+
     /// Receive a client hello and advance to the next server state.
     pub fn recv_client_hello(self, recv_buf: &[u8]) -> Result<Handshake<SendServerHello>> {
         let client_hmac = auth::Tag::from_slice(&recv_buf[..32]).unwrap();
@@ -402,7 +721,10 @@ impl Handshake<RecvClientHello> {
         let shared_secret_partial = SharedSecretPartial {
             ab: curve25519::scalarmult(&self.base.ephemeral_sk, &client_ephemeral_pk)
                 .or_else(|_| fn_error(ScalarMultSk::Ephemeral, ScalarMultPk::ClientEphemeral))?,
-            aB: curve25519::scalarmult(&self.base.sk.to_curve25519(), &client_ephemeral_pk)
+            aB: self
+                .base
+                .signer
+                .scalarmult(&client_ephemeral_pk)
                 .or_else(|_| fn_error(ScalarMultSk::LongTerm, ScalarMultPk::ClientEphemeral))?,
         };
         Ok(Handshake {
@@ -455,7 +777,7 @@ impl Handshake<RecvClientAuth> {
             &secretbox::Key(
                 sha256::hash(
                     &[
-                        self.base.net_id.as_ref(),
+                        net_id_bytes(&self.base.net_id),
                         self.state.shared_secret_partial.ab.as_ref(),
                         self.state.shared_secret_partial.aB.as_ref(),
                     ]
@@ -471,7 +793,7 @@ impl Handshake<RecvClientAuth> {
         if !ed25519::verify_detached(
             &client_sig,
             &[
-                self.base.net_id.as_ref(),
+                net_id_bytes(&self.base.net_id),
                 self.base.pk.as_ref(),
                 sha256::hash(self.state.shared_secret_partial.ab.as_ref()).as_ref(),
             ]
@@ -480,6 +802,9 @@ impl Handshake<RecvClientAuth> {
         ) {
             return Err(Error::RecvClientAuthEd25519);
         }
+        if self.base.reject_self_connection && client_pk == self.base.pk {
+            return Err(Error::SelfConnection);
+        }
         let fn_error = |a, b| Err(Error::RecvClientHelloScalarmult(a, b));
         let shared_secret = SharedSecret {
             ab: self.state.shared_secret_partial.ab,
@@ -509,19 +834,16 @@ pub const SERVER_ACCEPT_BYTES: usize = 80;
 impl Handshake<SendServerAccept> {
     /// Send a server accept and advance to the next server state.
     pub fn send_server_accept(self, send_buf: &mut [u8]) -> Handshake<Complete> {
-        let sig = ed25519::sign_detached(
-            &concat!(
-                auth::KEYBYTES
-                    + ed25519::SIGNATUREBYTES
-                    + ed25519::PUBLICKEYBYTES
-                    + sha256::DIGESTBYTES,
-                self.base.net_id.as_ref(),
-                self.state.client_sig.as_ref(),
-                self.state.client_pk.as_ref(),
-                sha256::hash(self.state.shared_secret.ab.as_ref()).as_ref()
-            ),
-            &self.base.sk,
-        );
+        let sig = self.base.signer.sign_detached(&concat!(
+            auth::KEYBYTES
+                + ed25519::SIGNATUREBYTES
+                + ed25519::PUBLICKEYBYTES
+                + sha256::DIGESTBYTES,
+            net_id_bytes(&self.base.net_id),
+            self.state.client_sig.as_ref(),
+            self.state.client_pk.as_ref(),
+            sha256::hash(self.state.shared_secret.ab.as_ref()).as_ref()
+        ));
         send_buf[secretbox::MACBYTES..].copy_from_slice(sig.as_ref());
         let tag = secretbox::seal_detached(
             &mut send_buf[secretbox::MACBYTES..],
@@ -529,7 +851,7 @@ impl Handshake<SendServerAccept> {
             &secretbox::Key(
                 sha256::hash(&concat!(
                     auth::KEYBYTES + curve25519::GROUPELEMENTBYTES * 3,
-                    self.base.net_id.as_ref(),
+                    net_id_bytes(&self.base.net_id),
                     self.state.shared_secret.ab.as_ref(),
                     self.state.shared_secret.aB.as_ref(),
                     self.state.shared_secret.Ab.as_ref()
@@ -558,6 +880,7 @@ impl Handshake<SendServerAccept> {
 /// stablish a secure authenticated channel.
 #[derive(Debug)]
 pub struct HandshakeComplete {
+    pub version: HandshakeVersion,
     pub net_id: auth::Key,
     pub pk: ed25519::PublicKey,
     pub ephemeral_pk: curve25519::GroupElement,
@@ -570,6 +893,7 @@ impl Handshake<Complete> {
     /// Create a `HandshakeComplete` out of a `Handshake` in the `Complete` state.
     pub fn complete(&self) -> HandshakeComplete {
         HandshakeComplete {
+            version: self.base.version,
             net_id: self.base.net_id.clone(),
             pk: self.base.pk,
             ephemeral_pk: self.base.ephemeral_pk.clone(),
@@ -580,10 +904,111 @@ impl Handshake<Complete> {
     }
 }
 
+/// The public information of a session established by a handshake, which contains no session key
+/// material and so can be safely handed to logging or metrics code.  Its `Debug` output also leaves
+/// out the `net_id`, which is secret in private networks.
+#[derive(Clone, PartialEq)]
+pub struct SessionInfo {
+    pub net_id: auth::Key,
+    pub peer_pk: ed25519::PublicKey,
+    pub ephemeral_pk: curve25519::GroupElement,
+    pub peer_ephemeral_pk: curve25519::GroupElement,
+}
+
+// The net_id of a private network is the secret capability key that gives access to it, so only its
+// `fingerprint` is printed.
+impl fmt::Debug for SessionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionInfo")
+            .field(
+                "net_id_fingerprint",
+                &fingerprint(net_id_bytes(&self.net_id)),
+            )
+            .field("peer_pk", &hex::encode(self.peer_pk))
+            .field("ephemeral_pk", &hex::encode(&self.ephemeral_pk))
+            .field("peer_ephemeral_pk", &hex::encode(&self.peer_ephemeral_pk))
+            .finish()
+    }
+}
+
+impl HandshakeComplete {
+    /// Returns the public `SessionInfo` of this handshake, leaving out the shared secret.
+    pub fn public_info(&self) -> SessionInfo {
+        SessionInfo {
+            net_id: self.net_id.clone(),
+            peer_pk: self.peer_pk,
+            ephemeral_pk: self.ephemeral_pk.clone(),
+            peer_ephemeral_pk: self.peer_ephemeral_pk.clone(),
+        }
+    }
+
+    /// Returns whether `self` and `other` are sessions between the same two peers in the same
+    /// network, regardless of their ephemeral keys and of which peer was the client.
+    pub fn same_peers(&self, other: &HandshakeComplete) -> bool {
+        self.net_id == other.net_id
+            && ((self.pk, self.peer_pk) == (other.pk, other.peer_pk)
+                || (self.pk, self.peer_pk) == (other.peer_pk, other.pk))
+    }
+
+    /// Encode the `HandshakeComplete` into `HANDSHAKE_COMPLETE_BYTES` bytes with a fixed layout:
+    /// the version byte followed by the net_id, pk, ephemeral_pk, peer_pk, peer_ephemeral_pk and
+    /// the ab, aB and Ab shared secrets, 32 bytes each.  Useful to pass a completed handshake to
+    /// another process.
+    ///
+    /// The encoding contains the shared secret, from which the boxstream keys are derived, so it
+    /// must be kept as secret as the session itself.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        concat!(
+            HANDSHAKE_COMPLETE_BYTES,
+            &[self.version.to_u8()],
+            net_id_bytes(&self.net_id),
+            self.pk.as_ref(),
+            self.ephemeral_pk.as_ref(),
+            self.peer_pk.as_ref(),
+            self.peer_ephemeral_pk.as_ref(),
+            self.shared_secret.ab.as_ref(),
+            self.shared_secret.aB.as_ref(),
+            self.shared_secret.Ab.as_ref()
+        )
+        .to_vec()
+    }
+
+    /// Decode a `HandshakeComplete` encoded with [`HandshakeComplete::to_bytes`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != HANDSHAKE_COMPLETE_BYTES {
+            return Err(Error::InvalidHandshakeComplete);
+        }
+        let version = HandshakeVersion::from_u8(bytes[0]).ok_or(Error::InvalidHandshakeComplete)?;
+        let field = |i: usize| &bytes[1 + i * 32..1 + (i + 1) * 32];
+        let group_element = |i| {
+            curve25519::GroupElement::from_slice(field(i)).ok_or(Error::InvalidHandshakeComplete)
+        };
+        Ok(Self {
+            version,
+            net_id: auth::Key::from_slice(field(0)).ok_or(Error::InvalidHandshakeComplete)?,
+            pk: ed25519::PublicKey::from_slice(field(1)).ok_or(Error::InvalidHandshakeComplete)?,
+            ephemeral_pk: group_element(2)?,
+            peer_pk: ed25519::PublicKey::from_slice(field(3))
+                .ok_or(Error::InvalidHandshakeComplete)?,
+            peer_ephemeral_pk: group_element(4)?,
+            shared_secret: SharedSecret {
+                ab: group_element(5)?,
+                aB: group_element(6)?,
+                Ab: group_element(7)?,
+            },
+        })
+    }
+}
+
+/// Size of the `HandshakeComplete` encoding.
+pub const HANDSHAKE_COMPLETE_BYTES: usize = 1 + 8 * 32;
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    const NET_ID_HEX: &str = "d4a1cb88a66f02f8db635ce26441cc5dac1b08420ceaac230839b755845a9ffb";
+
     #[test]
     fn test_handshake() {
         let net_id_hex = "d4a1cb88a66f02f8db635ce26441cc5dac1b08420ceaac230839b755845a9ffb";
@@ -651,4 +1076,588 @@ mod tests {
             complete_server.ephemeral_pk
         );
     }
+
+    #[test]
+    fn test_handshake_public_info() {
+        let net_id = auth::Key::from_slice(&hex::decode(NET_ID_HEX).unwrap()).unwrap();
+        let client = ed25519::gen_keypair();
+        let server = ed25519::gen_keypair();
+        let (complete_client, complete_server) = complete_handshake(&net_id, &client, &server);
+
+        let info_client = complete_client.public_info();
+        let info_server = complete_server.public_info();
+        assert_eq!(info_client.net_id, net_id);
+        assert_eq!(info_client.peer_pk, complete_client.peer_pk);
+        assert_eq!(info_client.ephemeral_pk, info_server.peer_ephemeral_pk);
+        assert_eq!(info_client.peer_ephemeral_pk, info_server.ephemeral_pk);
+
+        // The debug output has the public keys, but no secret
+        let debug = format!("{:?}", info_client);
+        assert!(debug.contains(&hex::encode(complete_client.peer_pk)));
+        assert!(!debug.contains(NET_ID_HEX));
+        let secrets = &complete_client.shared_secret;
+        for secret in &[&secrets.ab, &secrets.aB, &secrets.Ab] {
+            assert!(!debug.contains(&hex::encode(secret)));
+        }
+    }
+
+    #[test]
+    fn test_handshake_complete_bytes() {
+        let net_id = auth::Key::from_slice(&hex::decode(NET_ID_HEX).unwrap()).unwrap();
+        let client = ed25519::gen_keypair();
+        let server = ed25519::gen_keypair();
+        let (complete_client, _) = complete_handshake(&net_id, &client, &server);
+
+        let bytes = complete_client.to_bytes();
+        assert_eq!(bytes.len(), HANDSHAKE_COMPLETE_BYTES);
+        let decoded = HandshakeComplete::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.to_bytes(), bytes);
+        assert_eq!(
+            crate::KeyNonce::from_handshake(decoded),
+            crate::KeyNonce::from_handshake(complete_client)
+        );
+        match HandshakeComplete::from_bytes(&bytes[1..]) {
+            Err(Error::InvalidHandshakeComplete) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_handshake_version() {
+        let net_id = auth::Key::from_slice(&hex::decode(NET_ID_HEX).unwrap()).unwrap();
+        let client = ed25519::gen_keypair();
+        let server = ed25519::gen_keypair();
+        let (complete_client, complete_server) = complete_handshake(&net_id, &client, &server);
+        assert_eq!(complete_client.version, HandshakeVersion::V1);
+        assert_eq!(complete_server.version, HandshakeVersion::V1);
+
+        let hs_client = Handshake::new_client(net_id.clone(), client.0, client.1.clone())
+            .with_version(HandshakeVersion::V1);
+        let hs_server = Handshake::new_server(net_id.clone(), server.0, server.1.clone())
+            .with_version(HandshakeVersion::V1);
+        let (complete_client, complete_server) =
+            run_handshake(hs_client, hs_server, server.0).unwrap();
+        assert_eq!(complete_client.version, HandshakeVersion::V1);
+        assert_eq!(complete_server.version, HandshakeVersion::V1);
+        assert_eq!(complete_client.to_bytes()[0], HandshakeVersion::V1.to_u8());
+
+        assert_eq!(
+            HandshakeVersion::from_u8(HandshakeVersion::V1.to_u8()),
+            Some(HandshakeVersion::V1)
+        );
+        assert_eq!(HandshakeVersion::from_u8(0), None);
+        assert_eq!(HandshakeVersion::from_u8(2), None);
+    }
+
+    #[test]
+    fn test_handshake_lockstep_bytes() {
+        let net_id = auth::Key::from_slice(&hex::decode(NET_ID_HEX).unwrap()).unwrap();
+        let (client_pk, client_sk) = ed25519::gen_keypair();
+        let (server_pk, server_sk) = ed25519::gen_keypair();
+
+        let hs_client = Handshake::new_client(net_id.clone(), client_pk, client_sk);
+        let hs_server = Handshake::new_server(net_id, server_pk, server_sk);
+        let mut buf = [0; 128];
+
+        assert_eq!(hs_client.send_bytes(), hs_server.recv_bytes());
+        assert_eq!(hs_client.send_bytes(), CLIENT_HELLO_BYTES);
+        let hs_client = hs_client.send_client_hello(&mut buf[..CLIENT_HELLO_BYTES]);
+        let hs_server = hs_server
+            .recv_client_hello(&buf[..CLIENT_HELLO_BYTES])
+            .unwrap();
+
+        assert_eq!(hs_server.send_bytes(), hs_client.recv_bytes());
+        assert_eq!(hs_server.send_bytes(), SERVER_HELLO_BYTES);
+        let hs_server = hs_server.send_server_hello(&mut buf[..SERVER_HELLO_BYTES]);
+        let hs_client = hs_client
+            .recv_server_hello(&buf[..SERVER_HELLO_BYTES])
+            .unwrap();
+
+        assert_eq!(hs_client.send_bytes(), hs_server.recv_bytes());
+        assert_eq!(hs_client.send_bytes(), CLIENT_AUTH_BYTES);
+        let hs_client = hs_client
+            .send_client_auth(&mut buf[..CLIENT_AUTH_BYTES], server_pk)
+            .unwrap();
+        let hs_server = hs_server
+            .recv_client_auth(&mut buf[..CLIENT_AUTH_BYTES])
+            .unwrap();
+
+        assert_eq!(hs_server.send_bytes(), hs_client.recv_bytes());
+        assert_eq!(hs_server.send_bytes(), SERVER_ACCEPT_BYTES);
+        let _ = hs_server.send_server_accept(&mut buf[..SERVER_ACCEPT_BYTES]);
+        hs_client
+            .recv_server_accept(&mut buf[..SERVER_ACCEPT_BYTES])
+            .unwrap();
+    }
+
+    #[test]
+    fn test_handshake_step() {
+        let net_id = auth::Key::from_slice(&hex::decode(NET_ID_HEX).unwrap()).unwrap();
+        let (client_pk, client_sk) = ed25519::gen_keypair();
+        let (server_pk, server_sk) = ed25519::gen_keypair();
+        let mut buf = [0; 128];
+
+        let hs_client = Handshake::new_client(net_id.clone(), client_pk, client_sk);
+        let hs_server = Handshake::new_server(net_id, server_pk, server_sk);
+        assert_eq!(hs_client.step(), HandshakeStep::SendClientHello);
+        assert_eq!(hs_server.step(), HandshakeStep::RecvClientHello);
+
+        let hs_client = hs_client.send_client_hello(&mut buf[..CLIENT_HELLO_BYTES]);
+        assert_eq!(hs_client.step(), HandshakeStep::RecvServerHello);
+        let hs_server = hs_server
+            .recv_client_hello(&buf[..CLIENT_HELLO_BYTES])
+            .unwrap();
+        assert_eq!(hs_server.step(), HandshakeStep::SendServerHello);
+
+        let hs_server = hs_server.send_server_hello(&mut buf[..SERVER_HELLO_BYTES]);
+        assert_eq!(hs_server.step(), HandshakeStep::RecvClientAuth);
+        let hs_client = hs_client
+            .recv_server_hello(&buf[..SERVER_HELLO_BYTES])
+            .unwrap();
+        assert_eq!(hs_client.step(), HandshakeStep::SendClientAuth);
+
+        let hs_client = hs_client
+            .send_client_auth(&mut buf[..CLIENT_AUTH_BYTES], server_pk)
+            .unwrap();
+        assert_eq!(hs_client.step(), HandshakeStep::RecvServerAccept);
+        let hs_server = hs_server
+            .recv_client_auth(&mut buf[..CLIENT_AUTH_BYTES])
+            .unwrap();
+        assert_eq!(hs_server.step(), HandshakeStep::SendServerAccept);
+
+        let hs_server = hs_server.send_server_accept(&mut buf[..SERVER_ACCEPT_BYTES]);
+        assert_eq!(hs_server.step(), HandshakeStep::Complete);
+        let hs_client = hs_client
+            .recv_server_accept(&mut buf[..SERVER_ACCEPT_BYTES])
+            .unwrap();
+        assert_eq!(hs_client.step(), HandshakeStep::Complete);
+    }
+
+    #[test]
+    fn test_compute_shared_secrets() {
+        let keypair = |seed_hex: &str| {
+            ed25519::keypair_from_seed(
+                &ed25519::Seed::from_slice(&hex::decode(seed_hex).unwrap()).unwrap(),
+            )
+        };
+        let (client_pk, client_sk) =
+            keypair("0000000000000000000000000000000000000000000000000000000000000000");
+        let (server_pk, server_sk) =
+            keypair("0000000000000000000000000000000000000000000000000000000000000001");
+        let (client_ephemeral_pk, client_ephemeral_sk) =
+            keypair("0000000000000000000000000000000000000000000000000000000000000002");
+        let (server_ephemeral_pk, server_ephemeral_sk) =
+            keypair("0000000000000000000000000000000000000000000000000000000000000003");
+
+        let secrets = compute_shared_secrets(
+            &client_ephemeral_sk.to_curve25519(),
+            &server_ephemeral_pk.to_curve25519(),
+            &client_sk,
+            &server_pk,
+        )
+        .unwrap();
+        // Reference values computed independently of libsodium, with the X25519 function of
+        // RFC 7748 (python `cryptography`) on the keys converted to curve25519 by hand
+        // (clamped sha512 of the seed, and the birational map u = (1 + y) / (1 - y))
+        assert_eq!(
+            hex::encode(&secrets.ab),
+            "31e00a821389c3f4f25a8db13e48baa7c8fde208edde9f7a8e30409ef40d8c46"
+        );
+        assert_eq!(
+            hex::encode(&secrets.aB),
+            "7ec25079311f3363a038dffc26c7938f06545c14eb8214490377056ddc5e913c"
+        );
+        assert_eq!(
+            hex::encode(&secrets.Ab),
+            "3e4f406bc321cf0344f36b2481332aeb79d89a86ae32be22053c6312b9400909"
+        );
+
+        // The server obtains the same values from its side of the keys
+        let server_ephemeral_sk = server_ephemeral_sk.to_curve25519();
+        let client_ephemeral_pk = client_ephemeral_pk.to_curve25519();
+        assert_eq!(
+            secrets.ab,
+            curve25519::scalarmult(&server_ephemeral_sk, &client_ephemeral_pk).unwrap()
+        );
+        assert_eq!(
+            secrets.aB,
+            curve25519::scalarmult(&server_sk.to_curve25519(), &client_ephemeral_pk).unwrap()
+        );
+        assert_eq!(
+            secrets.Ab,
+            curve25519::scalarmult(&server_ephemeral_sk, &client_pk.to_curve25519()).unwrap()
+        );
+
+        // A handshake with the same ephemeral keys derives the same shared secret
+        let net_id = auth::Key::from_slice(&hex::decode(NET_ID_HEX).unwrap()).unwrap();
+        let hs_client =
+            Handshake::new_client_rng(net_id.clone(), client_pk, client_sk, &mut SeedRng(2));
+        let hs_server = Handshake::new_server_rng(net_id, server_pk, server_sk, &mut SeedRng(3));
+        assert_eq!(hs_client.base.ephemeral_pk, client_ephemeral_pk);
+        let (complete_client, complete_server) =
+            run_handshake(hs_client, hs_server, server_pk).unwrap();
+        assert_eq!(complete_client.shared_secret, secrets);
+        assert_eq!(complete_server.shared_secret, secrets);
+    }
+
+    // Rng that always draws the seed `0x00..00NN`, to create handshakes with known ephemeral keys.
+    struct SeedRng(u8);
+
+    impl RngCore for SeedRng {
+        fn next_u32(&mut self) -> u32 {
+            unimplemented!()
+        }
+        fn next_u64(&mut self) -> u64 {
+            unimplemented!()
+        }
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            dest.iter_mut().for_each(|b| *b = 0);
+            dest[dest.len() - 1] = self.0;
+        }
+        fn try_fill_bytes(
+            &mut self,
+            dest: &mut [u8],
+        ) -> core::result::Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    impl CryptoRng for SeedRng {}
+
+    #[test]
+    fn test_net_id_bytes() {
+        let net_id = auth::Key::from_slice(&hex::decode(NET_ID_HEX).unwrap()).unwrap();
+        assert_eq!(
+            &net_id_bytes(&net_id)[..],
+            &hex::decode(NET_ID_HEX).unwrap()[..]
+        );
+    }
+
+    // Perform a handshake between `hs_client` and `hs_server`, where `server_pk` is the public key
+    // of the server, passing each message to `on_message` along with the step that sent it before
+    // it is received.
+    fn run_handshake_with<F: FnMut(HandshakeStep, &mut [u8])>(
+        hs_client: Handshake<SendClientHello>,
+        hs_server: Handshake<RecvClientHello>,
+        server_pk: ed25519::PublicKey,
+        mut on_message: F,
+    ) -> Result<(HandshakeComplete, HandshakeComplete)> {
+        let mut buf = [0; 128];
+        let hs_client = hs_client.send_client_hello(&mut buf[..CLIENT_HELLO_BYTES]);
+        on_message(
+            HandshakeStep::SendClientHello,
+            &mut buf[..CLIENT_HELLO_BYTES],
+        );
+        let hs_server = hs_server.recv_client_hello(&buf[..CLIENT_HELLO_BYTES])?;
+        let hs_server = hs_server.send_server_hello(&mut buf[..SERVER_HELLO_BYTES]);
+        on_message(
+            HandshakeStep::SendServerHello,
+            &mut buf[..SERVER_HELLO_BYTES],
+        );
+        let hs_client = hs_client.recv_server_hello(&buf[..SERVER_HELLO_BYTES])?;
+        let hs_client = hs_client.send_client_auth(&mut buf[..CLIENT_AUTH_BYTES], server_pk)?;
+        on_message(HandshakeStep::SendClientAuth, &mut buf[..CLIENT_AUTH_BYTES]);
+        let hs_server = hs_server.recv_client_auth(&mut buf[..CLIENT_AUTH_BYTES])?;
+        let hs_server = hs_server.send_server_accept(&mut buf[..SERVER_ACCEPT_BYTES]);
+        on_message(
+            HandshakeStep::SendServerAccept,
+            &mut buf[..SERVER_ACCEPT_BYTES],
+        );
+        let hs_client = hs_client.recv_server_accept(&mut buf[..SERVER_ACCEPT_BYTES])?;
+        Ok((hs_client.complete(), hs_server.complete()))
+    }
+
+    // Perform a handshake between `hs_client` and `hs_server` without touching the messages.
+    fn run_handshake(
+        hs_client: Handshake<SendClientHello>,
+        hs_server: Handshake<RecvClientHello>,
+        server_pk: ed25519::PublicKey,
+    ) -> Result<(HandshakeComplete, HandshakeComplete)> {
+        run_handshake_with(hs_client, hs_server, server_pk, |_, _| {})
+    }
+
+    // Perform a handshake between the client keypair `client` and the server keypair `server`.
+    fn complete_handshake(
+        net_id: &auth::Key,
+        client: &(ed25519::PublicKey, ed25519::SecretKey),
+        server: &(ed25519::PublicKey, ed25519::SecretKey),
+    ) -> (HandshakeComplete, HandshakeComplete) {
+        let hs_client = Handshake::new_client(net_id.clone(), client.0, client.1.clone());
+        let hs_server = Handshake::new_server(net_id.clone(), server.0, server.1.clone());
+        run_handshake(hs_client, hs_server, server.0).unwrap()
+    }
+
+    #[test]
+    fn test_handshake_server_accept_replay() {
+        let net_id = auth::Key::from_slice(&hex::decode(NET_ID_HEX).unwrap()).unwrap();
+        let (client_pk, client_sk) = ed25519::gen_keypair();
+        let (server_pk, server_sk) = ed25519::gen_keypair();
+
+        // Run a session between the same peers, calling `on_message` with each message
+        let session = |on_message: &mut dyn FnMut(HandshakeStep, &mut [u8])| {
+            let hs_client = Handshake::new_client(net_id.clone(), client_pk, client_sk.clone());
+            let hs_server = Handshake::new_server(net_id.clone(), server_pk, server_sk.clone());
+            run_handshake_with(hs_client, hs_server, server_pk, on_message)
+        };
+
+        let mut server_accept_a = [0; SERVER_ACCEPT_BYTES];
+        session(&mut |step, msg| {
+            if step == HandshakeStep::SendServerAccept {
+                server_accept_a.copy_from_slice(msg);
+            }
+        })
+        .unwrap();
+
+        // The server accept of one session is rejected by the client of the other session
+        match session(&mut |step, msg| {
+            if step == HandshakeStep::SendServerAccept {
+                msg.copy_from_slice(&server_accept_a);
+            }
+        }) {
+            Err(Error::RecvServerAcceptSecretbox) => {}
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let net_id = auth::gen_key();
+        let (pk, sk) = ed25519::gen_keypair();
+        let (server_pk, _) = ed25519::gen_keypair();
+        assert_eq!(validate_config(&net_id, &pk, &sk, None), Ok(()));
+        assert_eq!(validate_config(&net_id, &pk, &sk, Some(&server_pk)), Ok(()));
+
+        // The public key of another keypair
+        assert_eq!(
+            validate_config(&net_id, &server_pk, &sk, None),
+            Err(ConfigError::KeypairMismatch)
+        );
+
+        // A secret key with a corrupted copy of the public key
+        let mut bad_sk = sk.clone();
+        bad_sk.0[40] ^= 1;
+        assert_eq!(
+            validate_config(&net_id, &pk, &bad_sk, None),
+            Err(ConfigError::KeypairMismatch)
+        );
+
+        // A server public key of small order
+        let mut low_order = [0; ed25519::PUBLICKEYBYTES];
+        low_order[0] = 1;
+        let server_pk = ed25519::PublicKey::from_slice(&low_order).unwrap();
+        assert_eq!(
+            validate_config(&net_id, &pk, &sk, Some(&server_pk)),
+            Err(ConfigError::InvalidServerKey)
+        );
+    }
+
+    #[test]
+    fn test_handshake_same_peers() {
+        let net_id = auth::gen_key();
+        let (peer_a, peer_b, peer_c) = (
+            ed25519::gen_keypair(),
+            ed25519::gen_keypair(),
+            ed25519::gen_keypair(),
+        );
+
+        let (a_client, b_server) = complete_handshake(&net_id, &peer_a, &peer_b);
+        let (a_client_2, _) = complete_handshake(&net_id, &peer_a, &peer_b);
+        assert_ne!(a_client.ephemeral_pk, a_client_2.ephemeral_pk);
+        assert!(a_client.same_peers(&a_client_2));
+        assert!(a_client.same_peers(&b_server));
+
+        // Same peers with the roles swapped
+        let (b_client, _) = complete_handshake(&net_id, &peer_b, &peer_a);
+        assert!(a_client.same_peers(&b_client));
+
+        // Another peer, or another network
+        let (a_client_c, _) = complete_handshake(&net_id, &peer_a, &peer_c);
+        assert!(!a_client.same_peers(&a_client_c));
+        let (a_client_net, _) = complete_handshake(&auth::gen_key(), &peer_a, &peer_b);
+        assert!(!a_client.same_peers(&a_client_net));
+    }
+
+    #[test]
+    fn test_handshake_server_hello_substitution() {
+        let net_id = auth::gen_key();
+        let client = ed25519::gen_keypair();
+        let server = ed25519::gen_keypair();
+        let session = |on_message: &mut dyn FnMut(HandshakeStep, &mut [u8])| {
+            let hs_client = Handshake::new_client(net_id.clone(), client.0, client.1.clone());
+            let hs_server = Handshake::new_server(net_id.clone(), server.0, server.1.clone());
+            run_handshake_with(hs_client, hs_server, server.0, on_message)
+        };
+
+        // Capture the server hello of a first session
+        let mut server_hello_1 = [0; SERVER_HELLO_BYTES];
+        session(&mut |step, msg| {
+            if step == HandshakeStep::SendServerHello {
+                server_hello_1.copy_from_slice(msg);
+            }
+        })
+        .unwrap();
+
+        // In a second session, the client receives the server hello of the first session.  It is
+        // authenticated with the net_id only, so the client accepts it, but the server can't open
+        // its client auth.
+        match session(&mut |step, msg| {
+            if step == HandshakeStep::SendServerHello {
+                msg.copy_from_slice(&server_hello_1);
+            }
+        }) {
+            Err(Error::RecvClientAuthSecretbox) => {}
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_handshake_signer() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        // Signer that counts its uses of the secret key
+        struct MockSigner {
+            sk: ed25519::SecretKey,
+            uses: Arc<AtomicUsize>,
+        }
+
+        impl Signer for MockSigner {
+            fn public_key(&self) -> ed25519::PublicKey {
+                self.sk.public_key()
+            }
+            fn sign_detached(&self, msg: &[u8]) -> ed25519::Signature {
+                self.uses.fetch_add(1, Ordering::SeqCst);
+                ed25519::sign_detached(msg, &self.sk)
+            }
+            fn scalarmult(
+                &self,
+                pk: &curve25519::GroupElement,
+            ) -> core::result::Result<curve25519::GroupElement, SignerError> {
+                self.uses.fetch_add(1, Ordering::SeqCst);
+                curve25519::scalarmult(&self.sk.to_curve25519(), pk)
+                    .map_err(|_| SignerError::ScalarMult)
+            }
+        }
+
+        let net_id = auth::gen_key();
+        let (client_pk, client_sk) = ed25519::gen_keypair();
+        let (server_pk, server_sk) = ed25519::gen_keypair();
+        let client_uses = Arc::new(AtomicUsize::new(0));
+        let server_uses = Arc::new(AtomicUsize::new(0));
+        let client_signer = MockSigner {
+            sk: client_sk,
+            uses: client_uses.clone(),
+        };
+        let server_signer = MockSigner {
+            sk: server_sk,
+            uses: server_uses.clone(),
+        };
+
+        let hs_client = Handshake::new_client_signer(net_id.clone(), client_signer);
+        let hs_server = Handshake::new_server_signer(net_id, server_signer);
+        let (complete_client, complete_server) =
+            run_handshake(hs_client, hs_server, server_pk).unwrap();
+
+        // One signature and one scalar multiplication on each side
+        assert_eq!(client_uses.load(Ordering::SeqCst), 2);
+        assert_eq!(server_uses.load(Ordering::SeqCst), 2);
+        assert_eq!(complete_client.pk, client_pk);
+        assert_eq!(complete_server.peer_pk, client_pk);
+        assert_eq!(complete_client.shared_secret, complete_server.shared_secret);
+    }
+
+    #[test]
+    fn test_handshake_app_version() {
+        let net_id = auth::gen_key();
+        let (client_pk, client_sk) = ed25519::gen_keypair();
+        let (server_pk, server_sk) = ed25519::gen_keypair();
+
+        let handshake = |client_app_version: &[u8], server_app_version: &[u8]| {
+            let hs_client = Handshake::new_client(net_id.clone(), client_pk, client_sk.clone())
+                .with_app_version(client_app_version);
+            let hs_server = Handshake::new_server(net_id.clone(), server_pk, server_sk.clone())
+                .with_app_version(server_app_version);
+            run_handshake(hs_client, hs_server, server_pk)
+        };
+
+        // Matching app versions derive the same boxstream keys on both sides
+        let (complete_client, complete_server) = handshake(b"app-1", b"app-1").unwrap();
+        assert_eq!(complete_client.net_id, complete_server.net_id);
+        assert_ne!(complete_client.net_id, net_id);
+        let (client_send, client_recv) = crate::KeyNonce::from_handshake(complete_client);
+        let (server_send, server_recv) = crate::KeyNonce::from_handshake(complete_server);
+        assert_eq!(client_send, server_recv);
+        assert_eq!(client_recv, server_send);
+
+        match handshake(b"app-1", b"app-2") {
+            Err(Error::RecvClientHelloAuth) => {}
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+        match handshake(b"app-1", b"") {
+            Err(Error::RecvClientHelloAuth) => {}
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+
+        // The empty app version is the standard net_id
+        let hs_client = Handshake::new_client(net_id.clone(), client_pk, client_sk.clone())
+            .with_app_version(b"");
+        let hs_server = Handshake::new_server(net_id.clone(), server_pk, server_sk);
+        let (complete_client, complete_server) =
+            run_handshake(hs_client, hs_server, server_pk).unwrap();
+        assert_eq!(complete_client.net_id, net_id);
+        assert_eq!(
+            crate::KeyNonce::from_handshake(complete_client).0,
+            crate::KeyNonce::from_handshake(complete_server).1
+        );
+    }
+
+    #[test]
+    fn test_handshake_self_connection() {
+        let net_id = auth::Key::from_slice(&hex::decode(NET_ID_HEX).unwrap()).unwrap();
+        let (pk, sk) = ed25519::gen_keypair();
+
+        let handshake = |hs_server: Handshake<RecvClientHello>| {
+            let hs_client = Handshake::new_client(net_id.clone(), pk, sk.clone());
+            run_handshake(hs_client, hs_server, pk)
+        };
+
+        let hs_server = Handshake::new_server(net_id.clone(), pk, sk.clone());
+        assert!(handshake(hs_server).is_ok());
+
+        let hs_server =
+            Handshake::new_server(net_id.clone(), pk, sk.clone()).reject_self_connection();
+        match handshake(hs_server) {
+            Err(Error::SelfConnection) => {}
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_handshake_rng() {
+        use rand_chacha::ChaCha20Rng;
+        use rand_core::SeedableRng;
+
+        let net_id = auth::Key::from_slice(&hex::decode(NET_ID_HEX).unwrap()).unwrap();
+        let (pk, sk) = ed25519::gen_keypair();
+
+        let mut rng_a = ChaCha20Rng::from_seed([7; 32]);
+        let mut rng_b = ChaCha20Rng::from_seed([7; 32]);
+        let mut rng_c = ChaCha20Rng::from_seed([8; 32]);
+
+        let hs_a = Handshake::new_client_rng(net_id.clone(), pk, sk.clone(), &mut rng_a);
+        let hs_b = Handshake::new_client_rng(net_id.clone(), pk, sk.clone(), &mut rng_b);
+        let hs_c = Handshake::new_client_rng(net_id.clone(), pk, sk.clone(), &mut rng_c);
+        assert_eq!(hs_a.base.ephemeral_pk, hs_b.base.ephemeral_pk);
+        assert_eq!(hs_a.base.ephemeral_sk, hs_b.base.ephemeral_sk);
+        assert_ne!(hs_a.base.ephemeral_pk, hs_c.base.ephemeral_pk);
+
+        // The rng state advances, so the next handshake gets fresh ephemeral keys
+        let hs_a = Handshake::new_server_rng(net_id.clone(), pk, sk.clone(), &mut rng_a);
+        let hs_b = Handshake::new_server_rng(net_id, pk, sk, &mut rng_b);
+        assert_eq!(hs_a.base.ephemeral_pk, hs_b.base.ephemeral_pk);
+        assert_ne!(hs_a.base.ephemeral_pk, hs_c.base.ephemeral_pk);
+    }
 }
@@ -0,0 +1,96 @@
+use log::warn;
+
+use crate::handshake;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Once,
+};
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+static WARN_UNINITIALIZED: Once = Once::new();
+
+/// Initialize the sodiumoxide crypto backend, which makes it thread safe and picks the best
+/// implementation of the primitives.  Calling it again is harmless.
+///
+/// The handshake functions of the `sync` and `async_std` modules call it automatically before
+/// generating any key, and fail with `Error::CryptoUnavailable` if it fails.  Calling it once at
+/// startup allows reporting that failure early.  The [`Handshake`](crate::handshake::Handshake)
+/// constructors don't check it: when using the state machine directly, call `init` first.
+#[allow(clippy::result_unit_err)]
+pub fn init() -> Result<(), ()> {
+    ensure_initialized_with(&INITIALIZED, sodiumoxide::init).map_err(|_| ())
+}
+
+/// Returns whether [`init`] has succeeded.
+pub(crate) fn initialized() -> bool {
+    INITIALIZED.load(Ordering::SeqCst)
+}
+
+/// Make sure that the crypto backend is initialized, calling [`init`] if needed, so that the
+/// handshake entry points fail with `Error::CryptoUnavailable` instead of using an unusable
+/// backend.
+pub(crate) fn ensure_initialized() -> handshake::Result<()> {
+    ensure_initialized_with(&INITIALIZED, sodiumoxide::init)
+}
+
+fn ensure_initialized_with<F: FnOnce() -> Result<(), ()>>(
+    initialized: &AtomicBool,
+    init: F,
+) -> handshake::Result<()> {
+    if initialized.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    init().map_err(|_| handshake::Error::CryptoUnavailable)?;
+    initialized.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Log a warning (only the first time) if the crypto backend is used before calling [`init`].
+pub(crate) fn check_initialized() {
+    if !initialized() {
+        WARN_UNINITIALIZED.call_once(|| {
+            warn!("kuska_handshake used before calling kuska_handshake::init()");
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::handshake::{Handshake, CLIENT_HELLO_BYTES};
+    use sodiumoxide::crypto::{auth, sign::ed25519};
+
+    #[test]
+    fn test_init() {
+        assert_eq!(init(), Ok(()));
+        assert!(initialized());
+
+        let net_id = auth::gen_key();
+        let (client_pk, client_sk) = ed25519::gen_keypair();
+        let (server_pk, server_sk) = ed25519::gen_keypair();
+        let hs_client = Handshake::new_client(net_id.clone(), client_pk, client_sk);
+        let hs_server = Handshake::new_server(net_id, server_pk, server_sk);
+        let mut buf = [0; CLIENT_HELLO_BYTES];
+        hs_client.send_client_hello(&mut buf);
+        assert!(hs_server.recv_client_hello(&buf).is_ok());
+    }
+
+    #[test]
+    fn test_ensure_initialized() {
+        // A backend that fails to initialize
+        let flag = AtomicBool::new(false);
+        match ensure_initialized_with(&flag, || Err(())) {
+            Err(handshake::Error::CryptoUnavailable) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert!(!flag.load(Ordering::SeqCst));
+
+        // A backend that initializes
+        assert!(ensure_initialized_with(&flag, || Ok(())).is_ok());
+        assert!(flag.load(Ordering::SeqCst));
+
+        // Once initialized the backend is not initialized again
+        assert!(ensure_initialized_with(&flag, || panic!("initialized twice")).is_ok());
+    }
+}
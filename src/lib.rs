@@ -11,7 +11,11 @@ pub mod sync;
 pub mod async_std;
 
 mod boxstream;
+mod capabilities;
 mod handshake;
+mod init;
 
 pub use boxstream::*;
+pub use capabilities::*;
 pub use handshake::*;
+pub use init::init;
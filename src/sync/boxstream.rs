@@ -1,6 +1,7 @@
 use std::{
-    io,
+    cmp, io,
     io::{Read, Write},
+    net::TcpStream,
 };
 
 use crate::{
@@ -10,48 +11,175 @@ use crate::{
     handshake::HandshakeComplete,
 };
 
+/// Encrypted boxstream reader.  Ciphertext is read from the underlying stream in chunks of up to
+/// the buffer capacity, so that several boxstream messages can be decrypted from a single read.
 pub struct BoxStreamRead<R> {
     stream: R,
     bs_recv: BoxStreamRecv,
+
+    // ciphertext read from `stream` pending to be decrypted
+    cipher: Box<[u8]>,
+    cipher_off: usize,
+    cipher_len: usize,
+
+    // decrypted plaintext pending to be read
+    plain: Box<[u8]>,
+    plain_off: usize,
+    plain_len: usize,
+}
+
+impl<R: Read> BoxStreamRead<R> {
+    /// Create a new `BoxStreamRead` that reads the ciphertext from `stream` and decrypts it with
+    /// `key_nonce`.
+    pub fn new(stream: R, key_nonce: KeyNonce) -> Self {
+        Self::with_capacity(stream, key_nonce, MSG_HEADER_LEN + MSG_BODY_MAX_LEN)
+    }
+
+    /// Like `new`, but reading up to `capacity` bytes of ciphertext from `stream` at once.  The
+    /// capacity is raised to fit at least one message of maximum size.
+    pub fn with_capacity(stream: R, key_nonce: KeyNonce, capacity: usize) -> Self {
+        let capacity = cmp::max(capacity, MSG_HEADER_LEN + MSG_BODY_MAX_LEN);
+        Self {
+            stream,
+            bs_recv: BoxStreamRecv::new(key_nonce),
+            cipher: vec![0; capacity].into_boxed_slice(),
+            cipher_off: 0,
+            cipher_len: 0,
+            plain: vec![0; MSG_BODY_MAX_LEN].into_boxed_slice(),
+            plain_off: 0,
+            plain_len: 0,
+        }
+    }
+
+    // Make sure there are at least `n` bytes of ciphertext in the buffer, reading as many bytes
+    // as fit from `stream` when there aren't.
+    fn fill_cipher(&mut self, n: usize) -> io::Result<()> {
+        if self.cipher_len - self.cipher_off >= n {
+            return Ok(());
+        }
+        // `BoxStreamRecv` never asks for more than a message of maximum size, which always fits
+        if n > self.cipher.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "boxstream message larger than the read buffer",
+            ));
+        }
+        // Keep the pending ciphertext (a partial message) at the beginning of the buffer
+        self.cipher.copy_within(self.cipher_off..self.cipher_len, 0);
+        self.cipher_len -= self.cipher_off;
+        self.cipher_off = 0;
+        while self.cipher_len < n {
+            match self.stream.read(&mut self.cipher[self.cipher_len..]) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "EOF before GOODBYE",
+                    ))
+                }
+                Ok(m) => self.cipher_len += m,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
 }
 
 impl<R: Read> Read for BoxStreamRead<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut enc = [0; MSG_HEADER_LEN + MSG_BODY_MAX_LEN];
-        let mut n = 0;
-        for _ in 0..2 {
+        // Decrypt messages until there is some plaintext to return
+        while self.plain_off == self.plain_len {
             let recv_bytes = self.bs_recv.recv_bytes();
-            self.stream.read_exact(&mut enc[..recv_bytes])?;
-            let (_, m) = match self.bs_recv.decrypt(&enc[..recv_bytes], buf)? {
+            self.fill_cipher(recv_bytes)?;
+            let enc = &self.cipher[self.cipher_off..self.cipher_off + recv_bytes];
+            let (_, m) = match self.bs_recv.decrypt(enc, &mut self.plain)? {
                 Decrypted::Goodbye => return Ok(0),
                 Decrypted::Some(v) => v,
             };
-            n += m;
+            self.cipher_off += recv_bytes;
+            self.plain_off = 0;
+            self.plain_len = m;
         }
+        let n = cmp::min(self.plain_len - self.plain_off, buf.len());
+        buf[..n].copy_from_slice(&self.plain[self.plain_off..self.plain_off + n]);
+        self.plain_off += n;
         Ok(n)
     }
 }
 
-pub struct BoxStreamWrite<W> {
+/// Encrypted boxstream writer.  By default each `write` is sent as its own boxstream message;
+/// with [`BoxStreamWrite::with_coalescing`] small writes are buffered and sent together.
+pub struct BoxStreamWrite<W: Write> {
     stream: W,
     bs_send: BoxStreamSend,
+
+    // plaintext pending to be sent in a single boxstream message, when coalescing writes
+    pending: Option<Vec<u8>>,
 }
 
 impl<W: Write> Write for BoxStreamWrite<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let mut enc = [0; MSG_HEADER_LEN + MSG_BODY_MAX_LEN];
-        let (n, m) = self.bs_send.encrypt(buf, &mut enc)?;
-        self.stream.write_all(&enc[..m])?;
+        let pending = match &mut self.pending {
+            Some(pending) => pending,
+            None => {
+                let mut enc = [0; MSG_HEADER_LEN + MSG_BODY_MAX_LEN];
+                let (n, m) = self.bs_send.encrypt(buf, &mut enc)?;
+                self.stream.write_all(&enc[..m])?;
+                return Ok(n);
+            }
+        };
+        let n = cmp::min(buf.len(), MSG_BODY_MAX_LEN - pending.len());
+        pending.extend_from_slice(&buf[..n]);
+        if pending.len() == MSG_BODY_MAX_LEN {
+            self.send_pending()?;
+        }
         Ok(n)
     }
 
     fn flush(&mut self) -> io::Result<()> {
+        self.send_pending()?;
         self.stream.flush()
     }
 }
 
 impl<W: Write> BoxStreamWrite<W> {
+    /// Create a new `BoxStreamWrite` that sends each `write` as a boxstream message.
+    pub fn new(stream: W, key_nonce: KeyNonce) -> Self {
+        Self {
+            stream,
+            bs_send: BoxStreamSend::new(key_nonce),
+            pending: None,
+        }
+    }
+
+    /// Create a new `BoxStreamWrite` that buffers writes up to the maximum boxstream message body
+    /// (`MSG_BODY_MAX_LEN`), sending them as a single message when the buffer is full or on
+    /// `flush`, to save the per message overhead of many small writes.  Like with a `BufWriter`,
+    /// buffered data still pending when the `BoxStreamWrite` is dropped is sent on a best effort
+    /// basis, ignoring any error, so `flush` or `goodbye` should be called to know it was sent.
+    pub fn with_coalescing(stream: W, key_nonce: KeyNonce) -> Self {
+        Self {
+            stream,
+            bs_send: BoxStreamSend::new(key_nonce),
+            pending: Some(Vec::with_capacity(MSG_BODY_MAX_LEN)),
+        }
+    }
+
+    // Send the buffered writes, if any, as a single boxstream message.
+    fn send_pending(&mut self) -> io::Result<()> {
+        if let Some(pending) = &mut self.pending {
+            if !pending.is_empty() {
+                let mut enc = [0; MSG_HEADER_LEN + MSG_BODY_MAX_LEN];
+                let (_, m) = self.bs_send.encrypt(pending, &mut enc)?;
+                pending.clear();
+                self.stream.write_all(&enc[..m])?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn goodbye(&mut self) -> io::Result<()> {
+        self.send_pending()?;
         let mut enc = [0; MSG_HEADER_LEN];
         let m = self.bs_send.encrypt_goodbye(&mut enc)?;
         self.stream.write_all(&enc[..m])?;
@@ -59,6 +187,12 @@ impl<W: Write> BoxStreamWrite<W> {
     }
 }
 
+impl<W: Write> Drop for BoxStreamWrite<W> {
+    fn drop(&mut self) {
+        let _ = self.send_pending();
+    }
+}
+
 pub struct BoxStream<R: Read, W: Write> {
     reader: BoxStreamRead<R>,
     writer: BoxStreamWrite<W>,
@@ -77,14 +211,8 @@ impl<R: Read, W: Write> BoxStream<R, W> {
         key_nonce_recv: KeyNonce,
     ) -> Self {
         Self {
-            reader: BoxStreamRead {
-                stream: read_stream,
-                bs_recv: BoxStreamRecv::new(key_nonce_recv),
-            },
-            writer: BoxStreamWrite {
-                stream: write_stream,
-                bs_send: BoxStreamSend::new(key_nonce_send),
-            },
+            reader: BoxStreamRead::new(read_stream, key_nonce_recv),
+            writer: BoxStreamWrite::new(write_stream, key_nonce_send),
         }
     }
 
@@ -98,14 +226,36 @@ impl<R: Read, W: Write> BoxStream<R, W> {
     }
 }
 
+impl HandshakeComplete {
+    /// Split a `TcpStream` over which this handshake was completed into the boxstream reader and
+    /// writer, each one with the key and nonce of its direction.  The `stream` is cloned with
+    /// `try_clone`, so the two halves can be moved to different threads.
+    pub fn split_tcp(
+        self,
+        stream: TcpStream,
+    ) -> io::Result<(BoxStreamRead<TcpStream>, BoxStreamWrite<TcpStream>)> {
+        let write_stream = stream.try_clone()?;
+        Ok(BoxStream::from_handshake(stream, write_stream, self).split_read_write())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use std::io::{Read, Write};
+    use std::{
+        cmp,
+        io::{Read, Write},
+        net::TcpListener,
+    };
+
+    use sodiumoxide::crypto::{auth, sign::ed25519};
+
+    use super::super::handshake::{handshake_client, handshake_server};
 
     use test_utils::net_sync::{net, net_fragment};
 
+    use crate::boxstream::{self, MSG_HEADER_DEC_LEN};
     use crossbeam::thread;
     use sodiumoxide::crypto::secretbox;
 
@@ -136,6 +286,198 @@ mod tests {
         (peer_a, peer_b)
     }
 
+    // Reader that counts the number of `read` calls and returns at most `max` bytes per call.
+    struct ChunkReader<R> {
+        stream: R,
+        max: usize,
+        reads: usize,
+    }
+
+    impl<R: Read> Read for ChunkReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            let n = cmp::min(buf.len(), self.max);
+            self.stream.read(&mut buf[..n])
+        }
+    }
+
+    // Encrypt `msgs` followed by a goodbye into a single buffer
+    fn encrypt_msgs(key_nonce: KeyNonce, msgs: &[Vec<u8>]) -> Vec<u8> {
+        let mut enc = Vec::new();
+        let mut bs_write = BoxStreamWrite::new(&mut enc, key_nonce);
+        for msg in msgs {
+            bs_write.write_all(msg).unwrap();
+        }
+        bs_write.goodbye().unwrap();
+        drop(bs_write);
+        enc
+    }
+
+    fn read_msgs<R: Read>(bs_read: &mut BoxStreamRead<R>, msgs: &[Vec<u8>]) {
+        for msg in msgs {
+            let mut buf = vec![0; msg.len()];
+            bs_read.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..], &msg[..]);
+        }
+        let mut buf = [0; 1];
+        assert_eq!(0, bs_read.read(&mut buf).unwrap());
+    }
+
+    #[test]
+    fn test_boxstream_sync_read_chunks() {
+        let (peer_a, peer_b) = load_peers();
+        let msgs: Vec<Vec<u8>> = vec![
+            (0..=255).collect(),
+            (0..5000).map(|b| (b % 99) as u8).collect(),
+            vec![7; 3],
+        ];
+        let enc = encrypt_msgs(peer_b.key_nonce_send, &msgs);
+
+        // All the messages are decrypted from a single read
+        let reader = ChunkReader {
+            stream: &enc[..],
+            max: enc.len(),
+            reads: 0,
+        };
+        let mut bs_read = BoxStreamRead::with_capacity(reader, peer_a.key_nonce_recv, 16384);
+        read_msgs(&mut bs_read, &msgs);
+        assert_eq!(1, bs_read.stream.reads);
+    }
+
+    #[test]
+    fn test_boxstream_sync_read_chunks_fragment() {
+        let (peer_a, peer_b) = load_peers();
+        let msgs: Vec<Vec<u8>> = vec![(0..=255).collect(), (0..5000).map(|b| b as u8).collect()];
+        let enc = encrypt_msgs(peer_b.key_nonce_send, &msgs);
+
+        // Messages are split across reads
+        let reader = ChunkReader {
+            stream: &enc[..],
+            max: 7,
+            reads: 0,
+        };
+        let mut bs_read = BoxStreamRead::new(reader, peer_a.key_nonce_recv);
+        read_msgs(&mut bs_read, &msgs);
+        assert_eq!(enc.len().div_ceil(7), bs_read.stream.reads);
+    }
+
+    // Encrypt the header of a message claiming a body of `body_len` bytes, as the first message
+    // that peer b sends to peer a.
+    fn forged_header(body_len: u16) -> Vec<u8> {
+        let key = secretbox::Key::from_slice(&hex::decode(KEY_B_HEX).unwrap()).unwrap();
+        let nonce = secretbox::Nonce::from_slice(&hex::decode(NONCE_B_HEX).unwrap()).unwrap();
+        let mut header = [0; MSG_HEADER_DEC_LEN];
+        header[..2].copy_from_slice(&body_len.to_be_bytes());
+        let tag = secretbox::seal_detached(&mut header, &nonce, &key);
+        [tag.as_ref(), &header[..]].concat()
+    }
+
+    #[test]
+    fn test_boxstream_sync_read_header_too_large() {
+        // The whole forged message is available
+        let mut enc = forged_header(5000);
+        enc.extend_from_slice(&[0; 5000]);
+
+        for capacity in &[0, 16384] {
+            let (peer_a, _) = load_peers();
+            let mut bs_read =
+                BoxStreamRead::with_capacity(&enc[..], peer_a.key_nonce_recv, *capacity);
+            let mut buf = [0; 16];
+            let err = bs_read.read(&mut buf).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            assert_eq!(
+                Some(&boxstream::Error::HeaderBodyTooLarge),
+                err.get_ref().and_then(|e| e.downcast_ref())
+            );
+        }
+    }
+
+    #[test]
+    fn test_boxstream_sync_write_coalescing() {
+        let (peer_a, peer_b) = load_peers();
+        let msg: Vec<u8> = (0..6000).map(|b| b as u8).collect();
+
+        let mut bs_write = BoxStreamWrite::with_coalescing(Vec::new(), peer_b.key_nonce_send);
+        for chunk in msg[..1000].chunks(10) {
+            bs_write.write_all(chunk).unwrap();
+        }
+        // Nothing is sent until flush
+        assert_eq!(0, bs_write.stream.len());
+        bs_write.flush().unwrap();
+        assert_eq!(MSG_HEADER_LEN + 1000, bs_write.stream.len());
+
+        // A full buffer is sent as a message without flush
+        for chunk in msg[1000..].chunks(10) {
+            bs_write.write_all(chunk).unwrap();
+        }
+        assert_eq!(2 * MSG_HEADER_LEN + 1000 + 4096, bs_write.stream.len());
+        bs_write.goodbye().unwrap();
+        assert_eq!(4 * MSG_HEADER_LEN + 6000, bs_write.stream.len());
+
+        let mut bs_read = BoxStreamRead::new(&bs_write.stream[..], peer_a.key_nonce_recv);
+        read_msgs(&mut bs_read, &[msg]);
+    }
+
+    #[test]
+    fn test_boxstream_sync_write_coalescing_drop() {
+        let (peer_a, peer_b) = load_peers();
+        let mut sent = Vec::new();
+
+        // The pending writes are sent when the writer is dropped
+        let mut bs_write = BoxStreamWrite::with_coalescing(&mut sent, peer_b.key_nonce_send);
+        bs_write.write_all(b"nice").unwrap();
+        bs_write.write_all(b"ssbing").unwrap();
+        drop(bs_write);
+        assert_eq!(MSG_HEADER_LEN + 10, sent.len());
+
+        let mut bs_read = BoxStreamRead::new(&sent[..], peer_a.key_nonce_recv);
+        let mut buf = [0; 10];
+        bs_read.read_exact(&mut buf).unwrap();
+        assert_eq!(b"nicessbing", &buf);
+    }
+
+    #[test]
+    fn test_boxstream_sync_split_tcp() {
+        let net_id = auth::Key::from_slice(&[7; auth::KEYBYTES]).unwrap();
+        let (client_pk, client_sk) = ed25519::gen_keypair();
+        let (server_pk, server_sk) = ed25519::gen_keypair();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let msg_client: Vec<u8> = (0..10000).map(|b| b as u8).collect();
+        let msg_server: Vec<u8> = (0..10000).map(|b| (b % 13) as u8).collect();
+
+        // Each peer reads and writes concurrently on a different thread
+        let peer = |stream: TcpStream, handshake_complete: HandshakeComplete, msg, expected| {
+            let (mut reader, mut writer) = handshake_complete.split_tcp(stream).unwrap();
+            thread::scope(|s| {
+                s.spawn(move |_| {
+                    writer.write_all(msg).unwrap();
+                    writer.goodbye().unwrap();
+                });
+                let mut buf = Vec::new();
+                reader.read_to_end(&mut buf).unwrap();
+                assert_eq!(&buf[..], expected);
+            })
+            .unwrap();
+        };
+
+        thread::scope(|s| {
+            let net_id_cpy = net_id.clone();
+            let (msg_client, msg_server) = (&msg_client[..], &msg_server[..]);
+            s.spawn(move |_| {
+                let (mut stream, _) = listener.accept().unwrap();
+                let complete =
+                    handshake_server(&mut stream, net_id_cpy, server_pk, server_sk).unwrap();
+                peer(stream, complete, msg_server, msg_client);
+            });
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let complete =
+                handshake_client(&mut stream, net_id, client_pk, client_sk, server_pk).unwrap();
+            peer(stream, complete, msg_client, msg_server);
+        })
+        .unwrap();
+    }
+
     #[test]
     fn test_boxstream_sync() {
         net(|a_rd, a_wr, b_rd, b_wr| boxstream_aux(a_rd, a_wr, b_rd, b_wr));
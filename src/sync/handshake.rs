@@ -1,13 +1,15 @@
 use std::{
     convert, io,
     io::{Read, Write},
+    net::TcpStream,
+    time::{Duration, Instant},
 };
 
 // use log::debug;
 use sodiumoxide::crypto::{auth, sign::ed25519};
 
 use super::error::{Error, Result};
-use crate::handshake::{self, Handshake, HandshakeComplete};
+use crate::handshake::{self, Handshake, HandshakeComplete, RecvClientHello, SendClientHello};
 
 impl convert::From<io::Error> for Error {
     fn from(error: io::Error) -> Self {
@@ -21,6 +23,53 @@ impl convert::From<handshake::Error> for Error {
     }
 }
 
+/// Number of consecutive 0-length reads after which [`read_exact_framed`] considers that the peer
+/// closed the stream.  This is a heuristic with no deeper meaning: it is large enough for streams
+/// that return a few spurious 0-length reads back to back, and small enough to detect a real close
+/// quickly.
+const ZERO_READS_MAX: usize = 8;
+
+/// Read exactly `buf.len()` bytes from `stream`, like `read_exact`, but without treating a single
+/// `Ok(0)` as the end of the stream.  Some `Read` implementations return 0-length reads
+/// transiently, so the peer is only considered closed after `ZERO_READS_MAX` consecutive 0-length
+/// reads, in which case an `io::ErrorKind::UnexpectedEof` error is returned.
+///
+/// The reads are retried immediately, without any backoff, so this only covers streams whose
+/// 0-length reads come in short bursts.  A stream that keeps returning `Ok(0)` while it waits for
+/// data is reported as closed, so it must block until data is available instead.
+fn read_exact_framed<T: Read>(stream: &mut T, mut buf: &mut [u8]) -> io::Result<()> {
+    let mut zero_reads = 0;
+    while !buf.is_empty() {
+        match stream.read(buf) {
+            Ok(0) => {
+                zero_reads += 1;
+                if zero_reads == ZERO_READS_MAX {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "peer closed during handshake",
+                    ));
+                }
+            }
+            Ok(n) => {
+                zero_reads = 0;
+                buf = &mut buf[n..];
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Perform the client side of the handshake over `stream`.
+///
+/// Each handshake message is read exactly, so no bytes beyond the handshake are consumed from
+/// `stream`.  A few consecutive 0-length reads are tolerated, but a `stream` that keeps returning
+/// `Ok(0)` is considered closed by the peer.  Each handshake message is written with `write_all`
+/// followed by `flush`, which together must make the bytes available to the peer.  If `stream`
+/// buffers its reads (for example through a `BufReader`), any bytes that the peer sent after the
+/// handshake are kept in that buffer, so the same reader must be used afterwards for the box
+/// stream.
 pub fn handshake_client<T: Read + Write>(
     stream: &mut T,
     net_id: auth::Key,
@@ -28,52 +77,156 @@ pub fn handshake_client<T: Read + Write>(
     sk: ed25519::SecretKey,
     server_pk: ed25519::PublicKey,
 ) -> Result<HandshakeComplete> {
+    crate::init::ensure_initialized()?;
+    handshake_client_with(stream, Handshake::new_client(net_id, pk, sk), server_pk)
+}
+
+/// Perform the client side of the handshake over `stream` starting from an already created
+/// `handshake`, which allows configuring it before running it.
+pub fn handshake_client_with<T: Read + Write>(
+    stream: &mut T,
+    handshake: Handshake<SendClientHello>,
+    server_pk: ed25519::PublicKey,
+) -> Result<HandshakeComplete> {
+    crate::init::ensure_initialized()?;
     let mut buf = [0; 128];
-    let handshake = Handshake::new_client(net_id, pk, sk);
 
     let mut send_buf = &mut buf[..handshake.send_bytes()];
     let handshake = handshake.send_client_hello(&mut send_buf);
     stream.write_all(&send_buf)?;
+    stream.flush()?;
 
     let mut recv_buf = &mut buf[..handshake.recv_bytes()];
-    stream.read_exact(&mut recv_buf)?;
+    read_exact_framed(stream, &mut recv_buf)?;
     let handshake = handshake.recv_server_hello(&recv_buf)?;
 
     let mut send_buf = &mut buf[..handshake.send_bytes()];
     let handshake = handshake.send_client_auth(&mut send_buf, server_pk)?;
     stream.write_all(&send_buf)?;
+    stream.flush()?;
 
     let mut recv_buf = &mut buf[..handshake.recv_bytes()];
-    stream.read_exact(&mut recv_buf)?;
+    read_exact_framed(stream, &mut recv_buf)?;
     let handshake = handshake.recv_server_accept(&mut recv_buf)?;
 
     Ok(handshake.complete())
 }
 
+/// Perform the server side of the handshake over `stream`.
+///
+/// Like in [`handshake_client`], no bytes beyond the handshake are consumed from `stream`.
 pub fn handshake_server<T: Read + Write>(
     stream: &mut T,
     net_id: auth::Key,
     pk: ed25519::PublicKey,
     sk: ed25519::SecretKey,
 ) -> Result<HandshakeComplete> {
+    crate::init::ensure_initialized()?;
+    handshake_server_with(stream, Handshake::new_server(net_id, pk, sk))
+}
+
+/// Perform the server side of the handshake over `stream` starting from an already created
+/// `handshake`, which allows configuring it before running it.
+pub fn handshake_server_with<T: Read + Write>(
+    stream: &mut T,
+    handshake: Handshake<RecvClientHello>,
+) -> Result<HandshakeComplete> {
+    handshake_server_aux(stream, handshake, read_exact_framed)
+}
+
+/// Like [`handshake_server_with`], but over a `TcpStream`, giving up with an
+/// `io::ErrorKind::TimedOut` error if the client hello is not received within `hello_timeout`, or
+/// if the rest of the handshake is not received within `timeout`.
+///
+/// The client hello is sent by a yet unauthenticated peer, so `hello_timeout` should be short to
+/// quickly drop peers that connect and then stall or send bytes slowly, instead of blocking the
+/// thread.  The read timeout of `stream` is changed during the handshake and restored afterwards.
+pub fn handshake_server_timeout(
+    stream: &mut TcpStream,
+    handshake: Handshake<RecvClientHello>,
+    hello_timeout: Duration,
+    timeout: Duration,
+) -> Result<HandshakeComplete> {
+    let read_timeout = stream.read_timeout()?;
+    let mut deadline = Instant::now() + hello_timeout;
+    let mut hello_received = false;
+    let res = handshake_server_aux(stream, handshake, |stream, buf| {
+        read_exact_deadline(stream, buf, deadline)?;
+        if !hello_received {
+            hello_received = true;
+            deadline = Instant::now() + timeout;
+        }
+        Ok(())
+    });
+    stream.set_read_timeout(read_timeout)?;
+    res
+}
+
+/// Read exactly `buf.len()` bytes from `stream`, failing with an `io::ErrorKind::TimedOut` error
+/// if they are not received before `deadline`.
+fn read_exact_deadline(
+    stream: &mut TcpStream,
+    mut buf: &mut [u8],
+    deadline: Instant,
+) -> io::Result<()> {
+    let timed_out = || io::Error::new(io::ErrorKind::TimedOut, "handshake timed out");
+    while !buf.is_empty() {
+        let timeout = deadline
+            .checked_duration_since(Instant::now())
+            .filter(|timeout| !timeout.is_zero())
+            .ok_or_else(timed_out)?;
+        stream.set_read_timeout(Some(timeout))?;
+        match stream.read(buf) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "peer closed during handshake",
+                ))
+            }
+            Ok(n) => buf = &mut buf[n..],
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            // The read timeout is reported as `WouldBlock` on unix and `TimedOut` on windows
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                return Err(timed_out())
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// Run the server side of the handshake, reading each message from `stream` with `recv`.
+fn handshake_server_aux<T, F>(
+    stream: &mut T,
+    handshake: Handshake<RecvClientHello>,
+    mut recv: F,
+) -> Result<HandshakeComplete>
+where
+    T: Read + Write,
+    F: FnMut(&mut T, &mut [u8]) -> io::Result<()>,
+{
+    crate::init::ensure_initialized()?;
     let mut buf = [0; 128];
-    let handshake = Handshake::new_server(net_id, pk, sk);
 
     let mut recv_buf = &mut buf[..handshake.recv_bytes()];
-    stream.read_exact(&mut recv_buf)?;
+    recv(stream, &mut recv_buf)?;
     let handshake = handshake.recv_client_hello(&recv_buf)?;
 
     let mut send_buf = &mut buf[..handshake.send_bytes()];
     let handshake = handshake.send_server_hello(&mut send_buf);
     stream.write_all(&send_buf)?;
+    stream.flush()?;
 
     let mut recv_buf = &mut buf[..handshake.recv_bytes()];
-    stream.read_exact(&mut recv_buf)?;
+    recv(stream, &mut recv_buf)?;
     let handshake = handshake.recv_client_auth(&mut recv_buf)?;
 
     let mut send_buf = &mut buf[..handshake.send_bytes()];
     let handshake = handshake.send_server_accept(&mut send_buf);
     stream.write_all(&send_buf)?;
+    stream.flush()?;
 
     Ok(handshake.complete())
 }
@@ -82,7 +235,10 @@ pub fn handshake_server<T: Read + Write>(
 mod tests {
     use super::*;
 
-    use std::io::{Read, Write};
+    use std::{
+        io::{BufReader, Read, Write},
+        net::{Shutdown, TcpListener},
+    };
 
     use test_utils::net_sync::{net, net_fragment};
 
@@ -137,6 +293,249 @@ mod tests {
         );
     }
 
+    // Join a reader and a writer into a single `Read + Write` stream.
+    struct ReadWrite<R, W>(R, W);
+
+    impl<R: Read, W> Read for ReadWrite<R, W> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl<R, W: Write> Write for ReadWrite<R, W> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.1.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.1.flush()
+        }
+    }
+
+    // The server sends `TRAILER` right after the handshake, and the client must find it unread in
+    // its stream once the handshake is complete.
+    fn handshake_trailer_aux<R: Read + Send, W: Write + Send>(
+        mut client_stream: ReadWrite<R, W>,
+        mut server_stream: impl Read + Write + Send,
+    ) {
+        const TRAILER: &[u8] = b"first bytes after the handshake";
+        let net_id = auth::Key::from_slice(&hex::decode(NET_ID_HEX).unwrap()).unwrap();
+        let (client_pk, client_sk) = ed25519::keypair_from_seed(
+            &ed25519::Seed::from_slice(&hex::decode(CLIENT_SEED_HEX).unwrap()).unwrap(),
+        );
+        let (server_pk, server_sk) = ed25519::keypair_from_seed(
+            &ed25519::Seed::from_slice(&hex::decode(SERVER_SEED_HEX).unwrap()).unwrap(),
+        );
+
+        thread::scope(|s| {
+            let net_id_cpy = net_id.clone();
+            let handle_server = s.spawn(move |_| {
+                handshake_server(&mut server_stream, net_id_cpy, server_pk, server_sk).unwrap();
+                server_stream.write_all(TRAILER).unwrap();
+                server_stream.flush().unwrap();
+            });
+            handshake_client(&mut client_stream, net_id, client_pk, client_sk, server_pk).unwrap();
+            handle_server.join().unwrap();
+        })
+        .unwrap();
+
+        let ReadWrite(mut reader, _) = client_stream;
+        let mut buf = [0; TRAILER.len()];
+        read_exact_framed(&mut reader, &mut buf).unwrap();
+        assert_eq!(&buf[..], TRAILER);
+    }
+
+    // Reader that returns a 0-length read before every read of `inner`.
+    struct ZeroReads<R> {
+        inner: R,
+        zero: bool,
+    }
+
+    impl<R: Read> Read for ZeroReads<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.zero = !self.zero;
+            if self.zero {
+                Ok(0)
+            } else {
+                self.inner.read(buf)
+            }
+        }
+    }
+
+    #[test]
+    fn test_handshake_sync_hello_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _stream_client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream_server, _) = listener.accept().unwrap();
+        let net_id = auth::Key::from_slice(&hex::decode(NET_ID_HEX).unwrap()).unwrap();
+        let (server_pk, server_sk) = ed25519::gen_keypair();
+        let handshake = Handshake::new_server(net_id, server_pk, server_sk);
+
+        // The client connects and never sends the client hello
+        let start = Instant::now();
+        let res = handshake_server_timeout(
+            &mut stream_server,
+            handshake,
+            Duration::from_millis(100),
+            Duration::from_secs(60),
+        );
+        match res {
+            Err(Error::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::TimedOut),
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(None, stream_server.read_timeout().unwrap());
+    }
+
+    #[test]
+    fn test_handshake_sync_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let net_id = auth::Key::from_slice(&hex::decode(NET_ID_HEX).unwrap()).unwrap();
+        let (client_pk, client_sk) = ed25519::gen_keypair();
+        let (server_pk, server_sk) = ed25519::gen_keypair();
+        let handshake = Handshake::new_server(net_id.clone(), server_pk, server_sk);
+
+        // A client that completes the handshake in time
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            handshake_client(&mut stream, net_id, client_pk, client_sk, server_pk).unwrap()
+        });
+        let (mut stream_server, _) = listener.accept().unwrap();
+        let read_timeout = Some(Duration::from_secs(30));
+        stream_server.set_read_timeout(read_timeout).unwrap();
+        let server_handshake = handshake_server_timeout(
+            &mut stream_server,
+            handshake,
+            Duration::from_secs(10),
+            Duration::from_secs(10),
+        )
+        .unwrap();
+        let client_handshake = client.join().unwrap();
+        assert!(client_handshake.same_peers(&server_handshake));
+        assert_eq!(read_timeout, stream_server.read_timeout().unwrap());
+
+        // The client sends the client hello and then stalls
+        let (client_pk, client_sk) = ed25519::gen_keypair();
+        let (server_pk, server_sk) = ed25519::gen_keypair();
+        let net_id = auth::Key::from_slice(&hex::decode(NET_ID_HEX).unwrap()).unwrap();
+        let handshake = Handshake::new_server(net_id.clone(), server_pk, server_sk);
+        let mut stream_client = TcpStream::connect(addr).unwrap();
+        let (mut stream_server, _) = listener.accept().unwrap();
+        let mut buf = [0; handshake::CLIENT_HELLO_BYTES];
+        Handshake::new_client(net_id, client_pk, client_sk).send_client_hello(&mut buf);
+        stream_client.write_all(&buf).unwrap();
+
+        let res = handshake_server_timeout(
+            &mut stream_server,
+            handshake,
+            Duration::from_secs(60),
+            Duration::from_millis(100),
+        );
+        match res {
+            Err(Error::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::TimedOut),
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_read_exact_framed() {
+        let mut reader = ZeroReads {
+            inner: &[1u8, 2, 3, 4][..],
+            zero: false,
+        };
+        let mut buf = [0; 4];
+        read_exact_framed(&mut reader, &mut buf[..2]).unwrap();
+        read_exact_framed(&mut reader, &mut buf[2..]).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+
+        let err = read_exact_framed(&mut reader, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_handshake_sync_zero_reads() {
+        net(|a_rd, a_wr, b, _| {
+            let inner = ZeroReads {
+                inner: a_rd,
+                zero: false,
+            };
+            handshake_trailer_aux(ReadWrite(inner, a_wr), b)
+        });
+    }
+
+    // Writer that only writes to `inner` on flush.
+    struct Withhold<W> {
+        inner: W,
+        pending: Vec<u8>,
+    }
+
+    impl<W: Write> Write for Withhold<W> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.pending.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.write_all(&self.pending)?;
+            self.pending.clear();
+            self.inner.flush()
+        }
+    }
+
+    #[test]
+    fn test_handshake_sync_withhold_until_flush() {
+        net(|a_rd, a_wr, b_rd, b_wr| {
+            let withhold = |inner| Withhold {
+                inner,
+                pending: Vec::new(),
+            };
+            handshake_trailer_aux(
+                ReadWrite(a_rd, withhold(a_wr)),
+                ReadWrite(b_rd, withhold(b_wr)),
+            )
+        });
+    }
+
+    #[test]
+    fn test_handshake_sync_no_over_read() {
+        net(|a_rd, a_wr, b, _| handshake_trailer_aux(ReadWrite(a_rd, a_wr), b));
+    }
+
+    #[test]
+    fn test_handshake_sync_bufreader() {
+        net(|a_rd, a_wr, b, _| handshake_trailer_aux(ReadWrite(BufReader::new(a_rd), a_wr), b));
+    }
+
+    #[test]
+    fn test_handshake_sync_self_connection() {
+        net(|stream_client, _, stream_server, _| {
+            let net_id = auth::Key::from_slice(&hex::decode(NET_ID_HEX).unwrap()).unwrap();
+            let (pk, sk) = ed25519::keypair_from_seed(
+                &ed25519::Seed::from_slice(&hex::decode(SERVER_SEED_HEX).unwrap()).unwrap(),
+            );
+
+            thread::scope(|s| {
+                let net_id_cpy = net_id.clone();
+                let sk_cpy = sk.clone();
+                let handle_client = s.spawn(move |_| {
+                    handshake_client(stream_client, net_id, pk, sk_cpy, pk).unwrap_err()
+                });
+                let handle_server = s.spawn(move |_| {
+                    let handshake =
+                        Handshake::new_server(net_id_cpy, pk, sk).reject_self_connection();
+                    let res = handshake_server_with(stream_server, handshake);
+                    stream_server.shutdown(Shutdown::Both).unwrap();
+                    res.unwrap_err()
+                });
+                handle_client.join().unwrap();
+                match handle_server.join().unwrap() {
+                    Error::Handshake(handshake::Error::SelfConnection) => {}
+                    err => panic!("unexpected error: {}", err),
+                }
+            })
+            .unwrap();
+        });
+    }
+
     #[test]
     fn test_handshake_sync() {
         net(|a, _, b, _| handshake_aux(a, b));
@@ -146,4 +545,45 @@ mod tests {
     fn test_handshake_sync_fragment() {
         net_fragment(5, |a, _, b, _| handshake_aux(a, b));
     }
+
+    // Transcript of a handshake where the client ephemeral keys are drawn from a `ChaCha20Rng`
+    // seeded with `[1; 32]` and the server ephemeral keys from one seeded with `[2; 32]`.
+    const TRANSCRIPT_CLIENT_HELLO_HEX: &str = "1cb10618aa75a57409d41714943d8253705740dfd9359d9473c42b39319066605574932e8f4b7a11c62c7ed977b4e13c76dda354523118b0aad36965a2876242";
+    const TRANSCRIPT_SERVER_HELLO_HEX: &str = "f5e5367def4867d660cdf88222a2f60b030a231e0e636eec904d0fd2a933f5bd85aade541a4e54e87dd90c6c034b810e85cee650ed7ce29954fd1d6bdaceee5b";
+    const TRANSCRIPT_CLIENT_AUTH_HEX: &str = "189601b58473d3f78fcebc8e72c6b8180be9b32148770664e417553d2d2de0f6cbf6c05988441dbf486b3070d3240049e8d5d1cfaade93be3445dee9963e104e665f25c53cc0cea139f189784a5083411e5923efc384b1d429bab1d655be360a8b5353a86b70f106adc7a0638949431b";
+    const TRANSCRIPT_SERVER_ACCEPT_HEX: &str = "3cc9074391395920320e31689b7e9f8b47040607a64f9e3b88f314c00b4a57772336ae85c0264467fd0e77380fbdcb0962f5722dd33461fd374afbe328697c76d83c9f7f30ff3a6ce543dea4f55bf87c";
+
+    #[test]
+    fn test_handshake_sync_transcript() {
+        use rand_chacha::ChaCha20Rng;
+        use rand_core::SeedableRng;
+        use test_utils::transcript::TranscriptStream;
+
+        let net_id = auth::Key::from_slice(&hex::decode(NET_ID_HEX).unwrap()).unwrap();
+        let (client_pk, client_sk) = ed25519::keypair_from_seed(
+            &ed25519::Seed::from_slice(&hex::decode(CLIENT_SEED_HEX).unwrap()).unwrap(),
+        );
+        let (server_pk, _) = ed25519::keypair_from_seed(
+            &ed25519::Seed::from_slice(&hex::decode(SERVER_SEED_HEX).unwrap()).unwrap(),
+        );
+
+        let server_transcript = [
+            hex::decode(TRANSCRIPT_SERVER_HELLO_HEX).unwrap(),
+            hex::decode(TRANSCRIPT_SERVER_ACCEPT_HEX).unwrap(),
+        ]
+        .concat();
+        let mut stream = TranscriptStream::new(server_transcript);
+        let mut rng = ChaCha20Rng::from_seed([1; 32]);
+        let handshake = Handshake::new_client_rng(net_id, client_pk, client_sk, &mut rng);
+        let handshake_complete = handshake_client_with(&mut stream, handshake, server_pk).unwrap();
+
+        assert_eq!(handshake_complete.peer_pk, server_pk);
+        assert_eq!(stream.unread(), 0);
+        let client_transcript = [
+            hex::decode(TRANSCRIPT_CLIENT_HELLO_HEX).unwrap(),
+            hex::decode(TRANSCRIPT_CLIENT_AUTH_HEX).unwrap(),
+        ]
+        .concat();
+        assert_eq!(stream.written(), &client_transcript[..]);
+    }
 }
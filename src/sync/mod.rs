@@ -1,7 +1,12 @@
 mod boxstream;
 mod error;
 mod handshake;
+mod stream;
 
-pub use boxstream::BoxStream;
+pub use boxstream::{BoxStream, BoxStreamRead, BoxStreamWrite};
 pub use error::{Error, Result};
-pub use handshake::{handshake_client, handshake_server};
+pub use handshake::{
+    handshake_client, handshake_client_with, handshake_server, handshake_server_timeout,
+    handshake_server_with,
+};
+pub use stream::{CountingStream, TeeStream};
@@ -0,0 +1,209 @@
+use std::io::{self, Read, Write};
+
+/// Stream adapter that counts the bytes read from and written to the wrapped stream.
+pub struct CountingStream<T> {
+    stream: T,
+    bytes_read: u64,
+    bytes_written: u64,
+}
+
+impl<T> CountingStream<T> {
+    /// Create a new `CountingStream` wrapping `stream` with both counters set to zero.
+    pub fn new(stream: T) -> Self {
+        Self {
+            stream,
+            bytes_read: 0,
+            bytes_written: 0,
+        }
+    }
+
+    /// Returns the number of bytes read from the wrapped stream.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Returns the number of bytes written to the wrapped stream.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Returns a reference to the wrapped stream.
+    pub fn get_ref(&self) -> &T {
+        &self.stream
+    }
+
+    /// Consumes the `CountingStream`, returning the wrapped stream.
+    pub fn into_inner(self) -> T {
+        self.stream
+    }
+}
+
+impl<T: Read> Read for CountingStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stream.read(buf)?;
+        self.bytes_read += n as u64;
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for CountingStream<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.stream.write(buf)?;
+        self.bytes_written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Stream adapter that copies all the bytes read from the wrapped stream into `read_sink` and all
+/// the bytes written to the wrapped stream into `write_sink`.
+///
+/// When wrapping the transport under a box stream only ciphertext is copied, which is useful to
+/// debug the protocol without leaking plaintext.
+///
+/// A failing sink never breaks the wrapped stream: the first sink error is kept and returned by
+/// [`TeeStream::sink_error`], and from then on the traffic is no longer copied into the sinks.
+pub struct TeeStream<T, R, W> {
+    stream: T,
+    read_sink: R,
+    write_sink: W,
+    sink_error: Option<io::Error>,
+}
+
+impl<T, R: Write, W: Write> TeeStream<T, R, W> {
+    /// Create a new `TeeStream` wrapping `stream`.
+    pub fn new(stream: T, read_sink: R, write_sink: W) -> Self {
+        Self {
+            stream,
+            read_sink,
+            write_sink,
+            sink_error: None,
+        }
+    }
+
+    /// Returns the first error returned by one of the sinks, after which the traffic stopped being
+    /// copied.
+    pub fn sink_error(&self) -> Option<&io::Error> {
+        self.sink_error.as_ref()
+    }
+
+    /// Consumes the `TeeStream`, returning the wrapped stream and the sinks as
+    /// `(stream, read_sink, write_sink)`.
+    pub fn into_inner(self) -> (T, R, W) {
+        (self.stream, self.read_sink, self.write_sink)
+    }
+}
+
+// Run `f` on `sink` unless a sink has failed before, keeping the error if it fails.
+fn tee<S, F>(sink: &mut S, sink_error: &mut Option<io::Error>, f: F)
+where
+    F: FnOnce(&mut S) -> io::Result<()>,
+{
+    if sink_error.is_none() {
+        if let Err(e) = f(sink) {
+            *sink_error = Some(e);
+        }
+    }
+}
+
+impl<T: Read, R: Write, W> Read for TeeStream<T, R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stream.read(buf)?;
+        tee(&mut self.read_sink, &mut self.sink_error, |sink| {
+            sink.write_all(&buf[..n])
+        });
+        Ok(n)
+    }
+}
+
+impl<T: Write, R, W: Write> Write for TeeStream<T, R, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.stream.write(buf)?;
+        tee(&mut self.write_sink, &mut self.sink_error, |sink| {
+            sink.write_all(&buf[..n])
+        });
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        tee(&mut self.write_sink, &mut self.sink_error, |sink| {
+            sink.flush()
+        });
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    #[test]
+    fn test_counting_stream() {
+        let mut stream = CountingStream::new(Cursor::new(vec![0; 16]));
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).unwrap();
+        stream.read_exact(&mut buf).unwrap();
+        stream.write_all(&[1, 2, 3]).unwrap();
+        assert_eq!(stream.bytes_read(), 10);
+        assert_eq!(stream.bytes_written(), 3);
+
+        // Reading at the end of the stream doesn't count any byte
+        let mut buf = [0; 32];
+        assert_eq!(stream.read(&mut buf).unwrap(), 3);
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        assert_eq!(stream.bytes_read(), 13);
+        assert_eq!(stream.into_inner().into_inner().len(), 16);
+    }
+
+    #[test]
+    fn test_tee_stream() {
+        let data: Vec<u8> = (0..32).collect();
+        let mut stream = TeeStream::new(Cursor::new(data.clone()), Vec::new(), Vec::new());
+        let mut buf = [0; 8];
+        stream.read_exact(&mut buf).unwrap();
+        stream.write_all(b"hello").unwrap();
+        stream.flush().unwrap();
+
+        let (stream, read_sink, write_sink) = stream.into_inner();
+        assert_eq!(&read_sink[..], &data[..8]);
+        assert_eq!(&write_sink[..], b"hello");
+        assert_eq!(&stream.into_inner()[8..13], b"hello");
+    }
+
+    // Sink that fails every write.
+    struct FailingSink;
+
+    impl Write for FailingSink {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("sink failed"))
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_tee_stream_sink_error() {
+        let data: Vec<u8> = (0..32).collect();
+        let mut stream = TeeStream::new(Cursor::new(data.clone()), FailingSink, Vec::new());
+        assert!(stream.sink_error().is_none());
+
+        // The bytes are still read from and written to the wrapped stream
+        let mut buf = [0; 8];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &data[..8]);
+        assert_eq!(stream.sink_error().unwrap().to_string(), "sink failed");
+        stream.write_all(b"hello").unwrap();
+        stream.flush().unwrap();
+
+        // No more traffic is copied after the error
+        let (stream, _, write_sink) = stream.into_inner();
+        assert!(write_sink.is_empty());
+        assert_eq!(&stream.into_inner()[8..13], b"hello");
+    }
+}
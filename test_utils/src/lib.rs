@@ -1,2 +1,3 @@
 pub mod net_async;
 pub mod net_sync;
+pub mod transcript;
@@ -0,0 +1,44 @@
+use std::io::{self, Cursor, Read, Write};
+
+// Stream that replays a recorded transcript of the peer messages: reads return the scripted
+// `peer` bytes (and EOF once they are exhausted), while all the writes are captured so that they
+// can be compared with the expected transcript.
+pub struct TranscriptStream {
+    peer: Cursor<Vec<u8>>,
+    written: Vec<u8>,
+}
+
+impl TranscriptStream {
+    pub fn new(peer: Vec<u8>) -> Self {
+        Self {
+            peer: Cursor::new(peer),
+            written: Vec::new(),
+        }
+    }
+
+    // Bytes written so far.
+    pub fn written(&self) -> &[u8] {
+        &self.written
+    }
+
+    // Number of scripted peer bytes that haven't been read yet.
+    pub fn unread(&self) -> usize {
+        self.peer.get_ref().len() - self.peer.position() as usize
+    }
+}
+
+impl Read for TranscriptStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.peer.read(buf)
+    }
+}
+
+impl Write for TranscriptStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.written.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}